@@ -1,303 +1,1268 @@
+use crate::cache::{CacheStats, DEFAULT_CACHE_SIZE};
+use crate::compact;
+use crate::durability::Durability;
 use crate::error::Error;
 use crate::node::Node;
 use crate::node_type::{Key, KeyValuePair, NodeType, Offset};
+use crate::overflow::OverflowPage;
 use crate::page::Page;
+use crate::page_layout::{
+    internal_cell_size, leaf_cell_size, INTERNAL_NODE_HEADER_SIZE, LEAF_NODE_HEADER_SIZE,
+    MAX_CELL_SIZE, MAX_KEY_SIZE, MAX_OVERFLOW_PREFIX_SIZE, OVERFLOW_DATA_SIZE, PTR_SIZE,
+    SUPERBLOCK_OFFSET, USABLE_PAGE_SIZE,
+};
 use crate::pager::Pager;
+use crate::range::Range;
+use crate::snapshot::Snapshot;
+use crate::storage::{Backend, FileBackend};
+use crate::superblock::Superblock;
+use crate::transaction::Transaction;
 use crate::wal::Wal;
 use std::cmp;
+use std::collections::HashSet;
 use std::convert::TryFrom;
-use std::path::Path;
+use std::ops::{Bound, RangeBounds, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Instant;
+use uuid::Uuid;
 
 /// B+Tree properties.
-pub const MAX_BRANCHING_FACTOR: usize = 200;
+/// The largest legal b parameter, for which a node holding 2*b empty keys fits in a page.
+pub const MAX_BRANCHING_FACTOR: usize = max_b_parameter(0, 0);
 pub const NODE_KEYS_LIMIT: usize = MAX_BRANCHING_FACTOR - 1;
+/// The smallest legal b parameter.
+pub const MIN_BRANCHING_FACTOR: usize = 2;
+
+/// The size of the wal, in bytes, past which it is checkpointed by default.
+pub const DEFAULT_AUTO_CHECKPOINT_SIZE: usize = 4 * 1024 * 1024;
+
+/// max_b_parameter returns the largest b parameter for which a leaf holding 2*b pairs, and an internal node
+/// holding 2*b children, with keys and values of the given sizes fit in a page.
+/// A tree may hold larger pairs as well, their nodes are split once their page is full.
+pub const fn max_b_parameter(key_size: usize, value_size: usize) -> usize {
+    // Values which do not fit a cell are moved to overflow pages.
+    let mut leaf_cell = leaf_cell_size(key_size, value_size);
+    if leaf_cell > MAX_CELL_SIZE {
+        leaf_cell = MAX_CELL_SIZE;
+    }
+    let leaf_pairs = (USABLE_PAGE_SIZE - LEAF_NODE_HEADER_SIZE) / leaf_cell;
+    // An internal node holds one key less than its children.
+    let internal_cell = internal_cell_size(key_size);
+    let internal_children =
+        (USABLE_PAGE_SIZE - INTERNAL_NODE_HEADER_SIZE + internal_cell) / (PTR_SIZE + internal_cell);
+    if leaf_pairs < internal_children {
+        leaf_pairs / 2
+    } else {
+        internal_children / 2
+    }
+}
 
 /// BTree struct represents an on-disk B+tree.
 /// Each node is persisted in the table file, the leaf nodes contain the values.
+///
+/// A BTree is a handle which can be cloned and shared between threads. Reads go through `&self`
+/// against a pinned root and run in parallel with each other and with the writer,
+/// writes are serialized so that a single writer at a time publishes new roots through the wal.
+#[derive(Clone)]
 pub struct BTree {
-    pager: Pager,
+    shared: Arc<Shared>,
+}
+
+/// Shared is the state of a tree shared by all of its handles.
+struct Shared {
+    /// The pager the tree is read from, replaced once the tree is vacuumed.
+    pager: RwLock<Arc<RwLock<Pager>>>,
+    /// Whether the tree was opened for reading only, writes fail with ReadOnly.
+    read_only: bool,
+    /// The writer, locked for the whole duration of a write or of a transaction.
+    writer: Mutex<Writer>,
+}
+
+/// Writer holds the state of a tree which is only accessed by writes.
+pub(crate) struct Writer {
+    /// Path to the tree file.
+    path: PathBuf,
+    /// Path to the wal of the tree file.
+    wal_path: PathBuf,
+    /// The backend both the tree file and the wal are stored with.
+    backend: Arc<dyn Backend>,
+    pager: Arc<RwLock<Pager>>,
     b: usize,
     wal: Wal,
+    /// The superblock as last written to the tree file, it is only rewritten by checkpoints.
+    superblock: Superblock,
+    durability: Durability,
+    /// The time commits were last flushed to disk.
+    last_sync: Instant,
+    /// The size of the wal past which it is checkpointed, None if it is only checkpointed manually.
+    auto_checkpoint: Option<usize>,
 }
 
 /// BtreeBuilder is a Builder for the BTree struct.
 pub struct BTreeBuilder {
     /// Path to the tree file.
-    path: &'static Path,
+    path: PathBuf,
+    /// Path to the wal, next to the tree file and named after it unless set.
+    wal_path: Option<PathBuf>,
     /// The BTree parameter, an inner node contains no more than 2*b-1 keys and no less than b-1 keys
     /// and no more than 2*b children and no less than b children.
     b: usize,
+    /// When commits are flushed to disk, on every commit by default.
+    durability: Durability,
+    /// The size of the wal, in bytes, past which it is checkpointed.
+    auto_checkpoint: Option<usize>,
+    /// Whether open creates a new empty tree if there is no tree file, true by default.
+    create_if_missing: bool,
+    /// Whether opening or building a tree fails if there is a tree file already.
+    error_if_exists: bool,
+    /// Whether the tree is opened for reading only.
+    read_only: bool,
+    /// The number of pages kept in memory.
+    cache_size: usize,
+    /// The backend the tree file and the wal are stored with, files by default.
+    backend: Arc<dyn Backend>,
 }
 
 impl BTreeBuilder {
     pub fn new() -> BTreeBuilder {
         BTreeBuilder {
-            path: Path::new(""),
+            path: PathBuf::new(),
+            wal_path: None,
             b: 0,
+            durability: Durability::default(),
+            auto_checkpoint: Some(DEFAULT_AUTO_CHECKPOINT_SIZE),
+            create_if_missing: true,
+            error_if_exists: false,
+            read_only: false,
+            cache_size: DEFAULT_CACHE_SIZE,
+            backend: Arc::new(FileBackend),
         }
     }
 
-    pub fn path(mut self, path: &'static Path) -> BTreeBuilder {
-        self.path = path;
+    pub fn path<P: AsRef<Path>>(mut self, path: P) -> BTreeBuilder {
+        self.path = path.as_ref().to_path_buf();
+        self
+    }
+
+    /// wal_path sets the path to the wal of the tree file, by default the path to the tree file suffixed with "-wal".
+    pub fn wal_path<P: AsRef<Path>>(mut self, wal_path: P) -> BTreeBuilder {
+        self.wal_path = Some(wal_path.as_ref().to_path_buf());
         self
     }
 
+    /// b_parameter sets the b parameter, between MIN_BRANCHING_FACTOR and MAX_BRANCHING_FACTOR.
     pub fn b_parameter(mut self, b: usize) -> BTreeBuilder {
         self.b = b;
         self
     }
 
+    pub fn durability(mut self, durability: Durability) -> BTreeBuilder {
+        self.durability = durability;
+        self
+    }
+
+    /// auto_checkpoint sets the size of the wal, in bytes, past which it is checkpointed on commit.
+    /// None disables automatic checkpoints, leaving them to BTree::checkpoint.
+    pub fn auto_checkpoint(mut self, wal_size: Option<usize>) -> BTreeBuilder {
+        self.auto_checkpoint = wal_size;
+        self
+    }
+
+    /// create_if_missing sets whether a missing tree file is created, otherwise open and build
+    /// fail with TreeNotFound.
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> BTreeBuilder {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// error_if_exists sets whether open and build fail with TreeAlreadyExists if there is a tree file already.
+    pub fn error_if_exists(mut self, error_if_exists: bool) -> BTreeBuilder {
+        self.error_if_exists = error_if_exists;
+        self
+    }
+
+    /// read_only sets whether the tree is opened for reading only, leaving both the tree file and the wal untouched.
+    pub fn read_only(mut self, read_only: bool) -> BTreeBuilder {
+        self.read_only = read_only;
+        self
+    }
+
+    /// cache_size sets the number of pages kept in memory by the page cache, DEFAULT_CACHE_SIZE by default.
+    /// Zero disables the cache, reading every page from disk.
+    pub fn cache_size(mut self, pages: usize) -> BTreeBuilder {
+        self.cache_size = pages;
+        self
+    }
+
+    /// backend sets the backend the tree file and the wal are stored with, their paths are resolved by the backend.
+    /// FileBackend by default, see also MmapBackend and MemoryBackend.
+    pub fn backend<B: Backend + 'static>(mut self, backend: B) -> BTreeBuilder {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// build creates a new empty BTree, truncating any existing tree file and wal.
     pub fn build(&self) -> Result<BTree, Error> {
-        if self.path.to_string_lossy() == "" {
-            return Err(Error::UnexpectedError);
-        }
-        if self.b == 0 {
-            return Err(Error::UnexpectedError);
+        self.validate()?;
+        if self.read_only {
+            return Err(Error::InvalidOptions(
+                "a read only tree cannot be built, use open".to_string(),
+            ));
         }
 
-        let mut pager = Pager::new(self.path)?;
-        let root = Node::new(NodeType::Leaf(vec![]), true, None);
-        let root_offset = pager.write_page(Page::try_from(&root)?)?;
-        let parent_directory = self.path.parent().unwrap_or_else(|| Path::new("/tmp"));
-        let mut wal = Wal::new(parent_directory.to_path_buf())?;
-        wal.set_root(root_offset)?;
+        let mut pager = Pager::new(self.backend.as_ref(), &self.path)?;
+        pager.set_cache_capacity(self.cache_size)?;
+        let (superblock, wal) = self.write_empty_tree(&mut pager)?;
+
+        let writer = Writer {
+            path: self.path.clone(),
+            wal_path: self.resolved_wal_path(),
+            backend: Arc::clone(&self.backend),
+            pager: Arc::new(RwLock::new(pager)),
+            b: self.b,
+            wal,
+            superblock,
+            durability: self.durability,
+            last_sync: Instant::now(),
+            auto_checkpoint: self.auto_checkpoint,
+        };
+        Ok(BTree::new(writer, false))
+    }
+
+    /// open opens the BTree persisted at the builder's path, recovering its
+    /// latest root from the wal. A new empty tree is created only if the file is empty.
+    pub fn open(&self) -> Result<BTree, Error> {
+        self.validate()?;
+
+        let mut pager = if self.read_only {
+            Pager::open_read_only(self.backend.as_ref(), &self.path)?
+        } else {
+            Pager::open(self.backend.as_ref(), &self.path)?
+        };
+        pager.set_cache_capacity(self.cache_size)?;
+        let (superblock, wal) = if pager.is_empty() {
+            if self.read_only {
+                return Err(Error::TreeNotFound(self.path.clone()));
+            }
+            self.write_empty_tree(&mut pager)?
+        } else {
+            // Refuse files which are not btree files or were written with a different format,
+            // before telling whether the header was damaged.
+            let page = pager.read_page(&Offset(SUPERBLOCK_OFFSET))?;
+            let damaged = !page.verify_checksum();
+            let superblock = Superblock::try_from(page)?;
+            let (mut superblock, mut wal) = if damaged {
+                self.recover_superblock()?
+            } else {
+                superblock.validate(self.b)?;
+                let wal = self.open_wal(&superblock)?;
+                (superblock, wal)
+            };
+            superblock.set_record(wal.get_record()?);
+            // Versions are only listed as long as they are logged.
+            let version = superblock.version;
+            superblock.oldest_version = cmp::max(
+                cmp::min(superblock.oldest_version, version),
+                wal.first_version(),
+            );
+            pager.set_version(version, superblock.oldest_version);
+            // The logged commit might not have been flushed by the process which wrote it,
+            // flush it before the pages it released are reused.
+            if !self.read_only {
+                pager.sync()?;
+                wal.sync()?;
+            }
+            pager.load_freelist(&superblock.record())?;
+            if damaged && !self.read_only {
+                pager.write_page_at_offset(
+                    Page::try_from(&superblock)?,
+                    &Offset(SUPERBLOCK_OFFSET),
+                )?;
+                pager.sync()?;
+            }
+            (superblock, wal)
+        };
+        // Make sure the logged root is readable before handing out the tree.
+        Node::try_from(pager.get_page(&superblock.root)?)?;
 
-        Ok(BTree {
-            pager,
+        let writer = Writer {
+            path: self.path.clone(),
+            wal_path: self.resolved_wal_path(),
+            backend: Arc::clone(&self.backend),
+            pager: Arc::new(RwLock::new(pager)),
             b: self.b,
             wal,
-        })
+            superblock,
+            durability: self.durability,
+            last_sync: Instant::now(),
+            auto_checkpoint: self.auto_checkpoint.filter(|_| !self.read_only),
+        };
+        Ok(BTree::new(writer, self.read_only))
+    }
+
+    /// open_wal opens the wal of the tree file with the given superblock,
+    /// falling back to the root recorded in the superblock if nothing is logged.
+    fn open_wal(&self, superblock: &Superblock) -> Result<Wal, Error> {
+        let wal_path = self.resolved_wal_path();
+        if !self.read_only {
+            let mut wal = Wal::open(self.backend.as_ref(), &wal_path, superblock.id)?;
+            if wal.is_empty() {
+                wal.append(&superblock.record())?;
+                wal.sync()?;
+            }
+            return Ok(wal);
+        }
+        // Falling back would require writing the wal.
+        if self.backend.exists(&wal_path) {
+            let wal = Wal::open_read_only(self.backend.as_ref(), &wal_path, superblock.id)?;
+            if !wal.is_empty() {
+                return Ok(wal);
+            }
+        }
+        Err(Error::InvalidOptions(format!(
+            "{} has nothing logged, the tree has to be opened for writing once to recover it",
+            wal_path.display()
+        )))
+    }
+
+    /// recover_superblock rebuilds a damaged superblock, e.g. torn while a checkpoint rewrote it, out of the builder's
+    /// options and the wal carrying the tree file id along with the last commit. Fails with Corruption if nothing was logged.
+    fn recover_superblock(&self) -> Result<(Superblock, Wal), Error> {
+        let corruption = || Error::Corruption {
+            offset: Offset(SUPERBLOCK_OFFSET),
+        };
+        let wal_path = self.resolved_wal_path();
+        if !self.backend.exists(&wal_path) {
+            return Err(corruption());
+        }
+        let id = Wal::read_id(self.backend.as_ref(), &wal_path)?.ok_or_else(corruption)?;
+        let wal = match self.read_only {
+            true => Wal::open_read_only(self.backend.as_ref(), &wal_path, id)?,
+            false => Wal::open(self.backend.as_ref(), &wal_path, id)?,
+        };
+        if wal.is_empty() {
+            return Err(corruption());
+        }
+        let mut superblock = Superblock::new(self.b, Offset(SUPERBLOCK_OFFSET));
+        superblock.id = id;
+        Ok((superblock, wal))
+    }
+
+    /// validate verifies the options are consistent and that the tree file exists (or not) as expected.
+    fn validate(&self) -> Result<(), Error> {
+        if self.path.as_os_str().is_empty() {
+            return Err(Error::InvalidOptions(
+                "a path to the tree file is required".to_string(),
+            ));
+        }
+        if self.b < MIN_BRANCHING_FACTOR || self.b > MAX_BRANCHING_FACTOR {
+            return Err(Error::InvalidOptions(format!(
+                "b parameter must be between {} and {} for nodes to fit in a page, got {}",
+                MIN_BRANCHING_FACTOR, MAX_BRANCHING_FACTOR, self.b
+            )));
+        }
+        if self.read_only && self.error_if_exists {
+            return Err(Error::InvalidOptions(
+                "error_if_exists cannot be set for a read only tree".to_string(),
+            ));
+        }
+        let exists = self.backend.exists(&self.path);
+        if exists && self.error_if_exists {
+            return Err(Error::TreeAlreadyExists(self.path.clone()));
+        }
+        if !exists && (self.read_only || !self.create_if_missing) {
+            return Err(Error::TreeNotFound(self.path.clone()));
+        }
+        Ok(())
+    }
+
+    /// resolved_wal_path returns the path to the wal of the tree file.
+    fn resolved_wal_path(&self) -> PathBuf {
+        match &self.wal_path {
+            Some(wal_path) => wal_path.clone(),
+            None => default_wal_path(&self.path),
+        }
+    }
+
+    /// write_empty_tree writes the superblock, reserving the first page of the file,
+    /// followed by an empty root leaf which is logged as the current root to a new wal.
+    fn write_empty_tree(&self, pager: &mut Pager) -> Result<(Superblock, Wal), Error> {
+        let mut superblock = Superblock::new(self.b, Offset(SUPERBLOCK_OFFSET));
+        let mut wal = Wal::new(
+            self.backend.as_ref(),
+            &self.resolved_wal_path(),
+            superblock.id,
+        )?;
+        pager.write_page(Page::try_from(&superblock)?)?;
+        let root = Node::new(NodeType::Leaf(vec![]), true, None);
+        let root_offset = pager.write_page(Page::try_from(&root)?)?;
+        let record = pager.commit(root_offset)?;
+        pager.sync()?;
+        wal.append(&record)?;
+        wal.sync()?;
+        pager.publish(record.root.clone());
+        superblock.set_record(record);
+        pager.write_page_at_offset(Page::try_from(&superblock)?, &Offset(SUPERBLOCK_OFFSET))?;
+        Ok((superblock, wal))
     }
 }
 
 impl Default for BTreeBuilder {
     // A default BTreeBuilder provides a builder with:
-    // - b parameter set to 200
+    // - b parameter set to MAX_BRANCHING_FACTOR
     // - path set to '/tmp/db'.
     fn default() -> Self {
         BTreeBuilder::new()
-            .b_parameter(200)
-            .path(Path::new("/tmp/db"))
+            .b_parameter(MAX_BRANCHING_FACTOR)
+            .path("/tmp/db")
+    }
+}
+
+/// default_wal_path returns the path to the wal of the tree file at the given path, e.g. "a.db-wal" for "a.db".
+fn default_wal_path(path: &Path) -> PathBuf {
+    suffixed_path(path, "-wal")
+}
+
+/// suffixed_path returns the path with the given suffix appended to its file name, e.g. "a.db-wal".
+fn suffixed_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut suffixed = path.as_os_str().to_owned();
+    suffixed.push(suffix);
+    PathBuf::from(suffixed)
+}
+
+/// is_same_file returns true if both paths lead to the same file.
+fn is_same_file(path: &Path, other: &Path) -> bool {
+    match (path.canonicalize(), other.canonicalize()) {
+        (Ok(path), Ok(other)) => path == other,
+        _ => path == other,
+    }
+}
+
+/// WriteMode determines how a write treats an existing (or missing) key.
+#[derive(PartialEq, Clone, Copy)]
+pub(crate) enum WriteMode {
+    /// Insert a new key, failing if it exists.
+    Insert,
+    /// Insert a new key or replace the value of an existing one.
+    Upsert,
+    /// Replace the value of an existing key, failing if it is missing.
+    Update,
+}
+
+/// child_index returns the index of the child of an internal node whose sub tree may hold the given key,
+/// as keys[i] is the largest key found under children[i].
+pub(crate) fn child_index(keys: &[Key], key: &str) -> usize {
+    keys.binary_search_by(|probe| probe.0.as_str().cmp(key))
+        .unwrap_or_else(|x| x)
+}
+
+/// overflow_prefix_len returns the length of the prefix of the value kept in the leaf cell
+/// if the pair does not fit a leaf cell, or None if it does.
+pub(crate) fn overflow_prefix_len(kv: &KeyValuePair) -> Option<usize> {
+    if kv.cell_size() <= MAX_CELL_SIZE {
+        return None;
+    }
+    let room = MAX_CELL_SIZE - leaf_cell_size(kv.key.len(), 0) - PTR_SIZE;
+    let mut prefix_len = cmp::min(MAX_OVERFLOW_PREFIX_SIZE, room);
+    while !kv.value.is_char_boundary(prefix_len) {
+        prefix_len -= 1;
+    }
+    Some(prefix_len)
+}
+
+/// read_node reads and deserializes the node at the given offset.
+pub(crate) fn read_node(pager: &RwLock<Pager>, offset: &Offset) -> Result<Node, Error> {
+    Node::try_from(pager.read()?.get_page(offset)?)
+}
+
+/// read_overflow reassembles a value continued in overflow pages.
+pub(crate) fn read_overflow(
+    pager: &RwLock<Pager>,
+    kv: KeyValuePair,
+) -> Result<KeyValuePair, Error> {
+    let KeyValuePair {
+        key,
+        value,
+        overflow,
+    } = kv;
+    let mut bytes = value.into_bytes();
+    let mut next = overflow;
+    while let Some(offset) = next {
+        let overflow_page = OverflowPage::try_from(pager.read()?.get_page(&offset)?)?;
+        bytes.extend_from_slice(&overflow_page.data);
+        next = overflow_page.next;
+    }
+    let value = String::from_utf8(bytes).map_err(|_| Error::UTF8Error)?;
+    Ok(KeyValuePair::new(key, value))
+}
+
+/// search_tree searches for a specific key in the tree rooted at the given offset.
+pub(crate) fn search_tree(
+    pager: &RwLock<Pager>,
+    root_offset: &Offset,
+    key: &str,
+) -> Result<KeyValuePair, Error> {
+    let root = read_node(pager, root_offset)?;
+    let pair = search_node(pager, root, key)?;
+    read_overflow(pager, pair)
+}
+
+/// search_node recursively searches a sub tree rooted at node for a key,
+/// the value of the pair found is not reassembled from its overflow pages.
+fn search_node(pager: &RwLock<Pager>, node: Node, search: &str) -> Result<KeyValuePair, Error> {
+    match node.node_type {
+        NodeType::Internal(children, keys) => {
+            let idx = child_index(&keys, search);
+            // Retrieve child page from disk and deserialize.
+            let child_offset = children.get(idx).ok_or(Error::UnexpectedError)?;
+            let child_node = read_node(pager, child_offset)?;
+            search_node(pager, child_node, search)
+        }
+        NodeType::Leaf(pairs) => {
+            if let Ok(idx) =
+                pairs.binary_search_by_key(&search.to_string(), |pair| pair.key.clone())
+            {
+                return Ok(pairs[idx].clone());
+            }
+            Err(Error::KeyNotFound)
+        }
+        NodeType::Unexpected => Err(Error::UnexpectedError),
+    }
+}
+
+/// prefix_successor returns the smallest string which is larger than every string starting with the prefix,
+/// or None if there is no such string.
+fn prefix_successor(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        // Skip over the surrogate range which is not made of valid chars.
+        let next = match last {
+            '\u{d7ff}' => Some('\u{e000}'),
+            _ => char::from_u32(last as u32 + 1),
+        };
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
     }
+    None
+}
+
+/// prefix_range returns the range of keys starting with the given prefix.
+pub(crate) fn prefix_range(prefix: &str) -> (Bound<String>, Bound<String>) {
+    let end = match prefix_successor(prefix) {
+        Some(successor) => Bound::Excluded(successor),
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix.to_string()), end)
 }
 
 impl BTree {
-    fn is_node_full(&self, node: &Node) -> Result<bool, Error> {
-        match &node.node_type {
-            NodeType::Leaf(pairs) => Ok(pairs.len() == (2 * self.b)),
-            NodeType::Internal(_, keys) => Ok(keys.len() == (2 * self.b - 1)),
-            NodeType::Unexpected => Err(Error::UnexpectedError),
+    fn new(writer: Writer, read_only: bool) -> BTree {
+        BTree {
+            shared: Arc::new(Shared {
+                pager: RwLock::new(Arc::clone(&writer.pager)),
+                read_only,
+                writer: Mutex::new(writer),
+            }),
         }
     }
 
-    fn is_node_underflow(&self, node: &Node) -> Result<bool, Error> {
-        match &node.node_type {
-            // A root cannot really be "underflowing" as it can contain less than b-1 keys / pointers.
-            NodeType::Leaf(pairs) => Ok(pairs.len() < (self.b - 1) && !node.is_root),
-            NodeType::Internal(_, keys) => Ok(keys.len() < (self.b - 1) && !node.is_root),
-            NodeType::Unexpected => Err(Error::UnexpectedError),
+    /// pager returns the pager the tree is currently read from.
+    fn pager(&self) -> Result<Arc<RwLock<Pager>>, Error> {
+        Ok(Arc::clone(&*self.shared.pager.read()?))
+    }
+
+    /// writer locks the writer, waiting for any write or transaction in progress to finish.
+    fn writer(&self) -> Result<MutexGuard<'_, Writer>, Error> {
+        Ok(self.shared.writer.lock()?)
+    }
+
+    /// check_writable fails with ReadOnly if the tree was opened for reading only.
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.shared.read_only {
+            return Err(Error::ReadOnly);
         }
+        Ok(())
     }
 
-    /// insert a key value pair possibly splitting nodes along the way.
-    pub fn insert(&mut self, kv: KeyValuePair) -> Result<(), Error> {
-        let root_offset = self.wal.get_root()?;
-        let root_page = self.pager.get_page(&root_offset)?;
-        let new_root_offset: Offset;
-        let mut new_root: Node;
-        let mut root = Node::try_from(root_page)?;
-        if self.is_node_full(&root)? {
-            // split the root creating a new root and child nodes along the way.
-            new_root = Node::new(NodeType::Internal(vec![], vec![]), true, None);
-            // write the new root to disk to aquire an offset for the new root.
-            new_root_offset = self.pager.write_page(Page::try_from(&new_root)?)?;
-            // set the old roots parent to the new root.
-            root.parent_offset = Some(new_root_offset.clone());
-            root.is_root = false;
-            // split the old root.
-            let (median, sibling) = root.split(self.b)?;
-            // write the old root with its new data to disk in a *new* location.
-            let old_root_offset = self.pager.write_page(Page::try_from(&root)?)?;
-            // write the newly created sibling to disk.
-            let sibling_offset = self.pager.write_page(Page::try_from(&sibling)?)?;
-            // update the new root with its children and key.
-            new_root.node_type =
-                NodeType::Internal(vec![old_root_offset, sibling_offset], vec![median]);
-            // write the new_root to disk.
-            self.pager
-                .write_page_at_offset(Page::try_from(&new_root)?, &new_root_offset)?;
-        } else {
-            new_root = root.clone();
-            new_root_offset = self.pager.write_page(Page::try_from(&new_root)?)?;
+    /// insert a key value pair possibly splitting nodes along the way,
+    /// fails with KeyAlreadyExists if the key is already in the tree.
+    pub fn insert(&self, kv: KeyValuePair) -> Result<(), Error> {
+        let mut transaction = self.begin()?;
+        transaction.insert(kv)?;
+        transaction.commit()
+    }
+
+    /// upsert inserts a key value pair or replaces the value of an existing key,
+    /// returning the previous value if there was one.
+    pub fn upsert(&self, kv: KeyValuePair) -> Result<Option<String>, Error> {
+        let mut transaction = self.begin()?;
+        let previous = transaction.upsert(kv)?;
+        transaction.commit()?;
+        Ok(previous)
+    }
+
+    /// update replaces the value of an existing key returning the previous value,
+    /// fails with KeyNotFound if the key is not in the tree.
+    pub fn update(&self, kv: KeyValuePair) -> Result<String, Error> {
+        let mut transaction = self.begin()?;
+        let previous = transaction.update(kv)?;
+        transaction.commit()?;
+        Ok(previous)
+    }
+
+    /// delete deletes a given key from the tree.
+    pub fn delete(&self, key: Key) -> Result<(), Error> {
+        let mut transaction = self.begin()?;
+        transaction.delete(key)?;
+        transaction.commit()
+    }
+
+    /// begin starts a transaction, its writes are published atomically with a single root once it is committed.
+    /// The transaction holds the writer until it is committed or rolled back, other writes wait for it
+    /// while reads keep going against the last committed root.
+    pub fn begin(&self) -> Result<Transaction<'_>, Error> {
+        self.check_writable()?;
+        let mut writer = self.writer()?;
+        let root_offset = writer.begin()?;
+        Ok(Transaction::new(writer, root_offset))
+    }
+
+    /// checkpoint records the last committed root in the superblock and flushes the tree file to disk,
+    /// then resets the wal to the last commit alone so that it does not grow forever.
+    /// Versions committed before the checkpoint are no longer readable by at_version,
+    /// snapshots still held keep reading them.
+    pub fn checkpoint(&self) -> Result<(), Error> {
+        self.check_writable()?;
+        self.writer()?.checkpoint()
+    }
+
+    /// sync flushes every commit made so far to disk regardless of the durability policy,
+    /// e.g. before shutting down a tree which does not flush on every commit.
+    pub fn sync(&self) -> Result<(), Error> {
+        // Nothing was written by a read only tree.
+        if self.shared.read_only {
+            return Ok(());
         }
-        // continue recursively.
-        self.insert_non_full(&mut new_root, new_root_offset.clone(), kv)?;
-        // finish by setting the root to its new copy.
-        self.wal.set_root(new_root_offset)
+        self.writer()?.sync()
     }
 
-    /// insert_non_full (recursively) finds a node rooted at a given non-full node.
-    /// to insert a given key-value pair. Here we assume the node is
-    /// already a copy of an existing node in a copy-on-write root to node traversal.
-    fn insert_non_full(
-        &mut self,
-        node: &mut Node,
-        node_offset: Offset,
-        kv: KeyValuePair,
-    ) -> Result<(), Error> {
-        match &mut node.node_type {
-            NodeType::Leaf(ref mut pairs) => {
-                let idx = pairs.binary_search(&kv).unwrap_or_else(|x| x);
-                pairs.insert(idx, kv);
-                self.pager
-                    .write_page_at_offset(Page::try_from(&*node)?, &node_offset)
-            }
-            NodeType::Internal(ref mut children, ref mut keys) => {
-                let idx = keys
-                    .binary_search(&Key(kv.key.clone()))
-                    .unwrap_or_else(|x| x);
-                let child_offset = children.get(idx).ok_or(Error::UnexpectedError)?.clone();
-                let child_page = self.pager.get_page(&child_offset)?;
-                let mut child = Node::try_from(child_page)?;
-                // Copy each branching-node on the root-to-leaf walk.
-                // write_page appends the given page to the db file thus creating a new node.
-                let new_child_offset = self.pager.write_page(Page::try_from(&child)?)?;
-                // Assign copied child at the proper place.
-                children[idx] = new_child_offset.to_owned();
-                if self.is_node_full(&child)? {
-                    // split will split the child at b leaving the [0, b-1] keys
-                    // while moving the set of [b, 2b-1] keys to the sibling.
-                    let (median, mut sibling) = child.split(self.b)?;
-                    self.pager
-                        .write_page_at_offset(Page::try_from(&child)?, &new_child_offset)?;
-                    // Write the newly created sibling to disk.
-                    let sibling_offset = self.pager.write_page(Page::try_from(&sibling)?)?;
-                    // Siblings keys are larger than the splitted child thus need to be inserted
-                    // at the next index.
-                    children.insert(idx + 1, sibling_offset.clone());
-                    keys.insert(idx, median.clone());
+    /// snapshot returns a read-only view of the tree as of its current root,
+    /// which is not affected by writes to the tree.
+    pub fn snapshot(&self) -> Result<Snapshot, Error> {
+        Snapshot::latest(self.pager()?)
+    }
 
-                    // Write the parent page to disk.
-                    self.pager
-                        .write_page_at_offset(Page::try_from(&*node)?, &node_offset)?;
-                    // Continue recursively.
-                    if kv.key <= median.0 {
-                        self.insert_non_full(&mut child, new_child_offset, kv)
-                    } else {
-                        self.insert_non_full(&mut sibling, sibling_offset, kv)
-                    }
-                } else {
-                    self.pager
-                        .write_page_at_offset(Page::try_from(&*node)?, &node_offset)?;
-                    self.insert_non_full(&mut child, new_child_offset, kv)
-                }
-            }
-            NodeType::Unexpected => Err(Error::UnexpectedError),
+    /// versions returns the versions of the tree which can still be read using at_version,
+    /// each committed root is a new version of the tree.
+    /// Older versions are no longer readable once the pages they reference are reused.
+    pub fn versions(&self) -> Result<RangeInclusive<usize>, Error> {
+        let pager = self.pager()?;
+        let pager = pager.read()?;
+        Ok(pager.oldest_version()..=pager.root_version())
+    }
+
+    /// at_version returns a read-only view of the tree as of the given version,
+    /// fails with VersionNotFound if the version is not readable.
+    /// The root of the version is looked up in the wal, waiting for any write in progress.
+    pub fn at_version(&self, version: usize) -> Result<Snapshot, Error> {
+        if !self.versions()?.contains(&version) {
+            return Err(Error::VersionNotFound);
         }
+        let mut writer = self.writer()?;
+        let root_offset = writer.wal.get_root_at(version)?;
+        Snapshot::at_version(self.pager()?, root_offset, version)
+    }
+
+    /// collect_garbage frees the pages which are not reachable from the current root or from the root
+    /// of any snapshot still held, returning the number of pages freed. Freed pages are reused by later
+    /// writes before the file is extended, thus any past version which is not held is no longer readable.
+    /// The freed pages are persisted to the free list by the following commit and reused once it is flushed.
+    pub fn collect_garbage(&self) -> Result<usize, Error> {
+        self.check_writable()?;
+        self.writer()?.collect_garbage()
+    }
+
+    /// compact writes the live pairs of the tree into a new tree file at dest along with a fresh wal
+    /// named after it, packing the leaves densely and in key order at the start of the file.
+    /// Fails with InvalidOptions if either is the tree file or its wal, and with TreeAlreadyExists
+    /// if either exists already.
+    pub fn compact(&self, dest: &Path) -> Result<(), Error> {
+        self.writer()?.compact(dest)
+    }
+
+    /// vacuum compacts the tree in place, the compacted file replaces the tree file once fully written.
+    /// The tree is checkpointed first, the compacted file is written next to the tree file suffixed with "-vacuum".
+    /// The logged versions are dropped and the compacted tree starts over from version one,
+    /// snapshots still held keep reading the previous file.
+    pub fn vacuum(&self) -> Result<(), Error> {
+        self.check_writable()?;
+        let mut writer = self.writer()?;
+        writer.vacuum()?;
+        // Reads move over to the compacted file before the writer is released.
+        *self.shared.pager.write()? = Arc::clone(&writer.pager);
+        Ok(())
+    }
+
+    /// cache_stats returns the number of page reads served by the page cache (hits)
+    /// and the number of those read from disk (misses) since the tree was opened, or last vacuumed.
+    pub fn cache_stats(&self) -> Result<CacheStats, Error> {
+        self.pager()?.read()?.cache_stats()
     }
 
     /// search searches for a specific key in the BTree.
-    pub fn search(&mut self, key: String) -> Result<KeyValuePair, Error> {
-        let root_offset = self.wal.get_root()?;
-        let root_page = self.pager.get_page(&root_offset)?;
-        let root = Node::try_from(root_page)?;
-        self.search_node(root, &key)
+    pub fn search(&self, key: String) -> Result<KeyValuePair, Error> {
+        self.snapshot()?.search(key)
+    }
+
+    /// range returns a double-ended iterator over the key-value pairs within the given bounds in key order,
+    /// bounds may be inclusive, exclusive or unbounded as in `std::collections::BTreeMap::range`.
+    /// The iterator reads the tree as of its current root, see snapshot.
+    pub fn range<K, R>(&self, range: R) -> Result<Range, Error>
+    where
+        K: AsRef<str>,
+        R: RangeBounds<K>,
+    {
+        Ok(Range::new(self.snapshot()?, range))
+    }
+
+    /// scan_prefix returns a double-ended iterator over the key-value pairs whose key starts with the given prefix.
+    /// The scan starts at the leaf holding the prefix and stops as soon as it walks past the keys sharing it.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Range, Error> {
+        Ok(Range::new(self.snapshot()?, prefix_range(prefix)))
+    }
+
+    /// iter returns a double-ended iterator over all key-value pairs in key order.
+    pub fn iter(&self) -> Result<Range, Error> {
+        self.range::<String, _>(..)
     }
 
-    /// search_node recursively searches a sub tree rooted at node for a key.
-    fn search_node(&mut self, node: Node, search: &str) -> Result<KeyValuePair, Error> {
+    /// print_sub_tree is a helper function for recursively printing the nodes rooted at a node given by its offset.
+    fn print_sub_tree(
+        &self,
+        pager: &RwLock<Pager>,
+        prefix: String,
+        offset: Offset,
+    ) -> Result<(), Error> {
+        println!("{}Node at offset: {}", prefix, offset.0);
+        let curr_prefix = format!("{}|->", prefix);
+        let node = read_node(pager, &offset)?;
         match node.node_type {
             NodeType::Internal(children, keys) => {
-                let idx = keys
-                    .binary_search(&Key(search.to_string()))
-                    .unwrap_or_else(|x| x);
-                // Retrieve child page from disk and deserialize.
-                let child_offset = children.get(idx).ok_or(Error::UnexpectedError)?;
-                let page = self.pager.get_page(child_offset)?;
-                let child_node = Node::try_from(page)?;
-                self.search_node(child_node, search)
+                println!("{}Keys: {:?}", curr_prefix, keys);
+                println!("{}Children: {:?}", curr_prefix, children);
+                let child_prefix = format!("{}   |  ", prefix);
+                for child_offset in children {
+                    self.print_sub_tree(pager, child_prefix.clone(), child_offset)?;
+                }
+                Ok(())
             }
             NodeType::Leaf(pairs) => {
-                if let Ok(idx) =
-                    pairs.binary_search_by_key(&search.to_string(), |pair| pair.key.clone())
-                {
-                    return Ok(pairs[idx].clone());
-                }
-                Err(Error::KeyNotFound)
+                println!("{}Key value pairs: {:?}", curr_prefix, pairs);
+                Ok(())
             }
             NodeType::Unexpected => Err(Error::UnexpectedError),
         }
     }
 
-    /// delete deletes a given key from the tree.
-    pub fn delete(&mut self, key: Key) -> Result<(), Error> {
-        let root_offset = self.wal.get_root()?;
-        let root_page = self.pager.get_page(&root_offset)?;
-        // Shadow the new root and rewrite it.
-        let mut new_root = Node::try_from(root_page)?;
-        let new_root_page = Page::try_from(&new_root)?;
-        let new_root_offset = self.pager.write_page(new_root_page)?;
-        self.delete_key_from_subtree(key, &mut new_root, &new_root_offset)?;
-        self.wal.set_root(new_root_offset)
+    /// print is a helper for recursively printing the tree.
+    pub fn print(&self) -> Result<(), Error> {
+        println!();
+        let snapshot = self.snapshot()?;
+        self.print_sub_tree(snapshot.pager(), "".to_string(), snapshot.root().clone())
     }
+}
 
-    /// delete key from subtree recursively traverses a tree rooted at a node in certain offset
-    /// until it finds the given key and delete the key-value pair. Here we assume the node is
-    /// already a copy of an existing node in a copy-on-write root to node traversal.
-    fn delete_key_from_subtree(
-        &mut self,
-        key: Key,
-        node: &mut Node,
-        node_offset: &Offset,
-    ) -> Result<(), Error> {
-        match &mut node.node_type {
-            NodeType::Leaf(ref mut pairs) => {
-                let key_idx = pairs
-                    .binary_search_by_key(&key, |kv| Key(kv.key.clone()))
-                    .map_err(|_| Error::KeyNotFound)?;
-                pairs.remove(key_idx);
-                self.pager
-                    .write_page_at_offset(Page::try_from(&*node)?, node_offset)?;
-                // Check for underflow - if it occures,
-                // we need to merge with a sibling.
+impl Writer {
+    /// is_node_full checks whether a node has to be split before inserting the given pair below it;
+    /// A node is full once it holds the maximum number of keys or does not have enough room left in its page,
+    /// for a leaf that is room for the pair itself and for an internal node room for the largest possible key.
+    fn is_node_full(&self, node: &Node, kv: &KeyValuePair) -> Result<bool, Error> {
+        match &node.node_type {
+            NodeType::Leaf(pairs) => {
+                Ok(pairs.len() >= (2 * self.b) || node.size() + kv.cell_size() > USABLE_PAGE_SIZE)
+            }
+            NodeType::Internal(_, keys) => Ok(keys.len() >= (2 * self.b - 1)
+                || node.size() + PTR_SIZE + internal_cell_size(MAX_KEY_SIZE) > USABLE_PAGE_SIZE),
+            NodeType::Unexpected => Err(Error::UnexpectedError),
+        }
+    }
+
+    /// fits checks whether a node (e.g. the result of a merge) can be written to a single page
+    /// without exceeding the maximum number of keys.
+    fn fits(&self, node: &Node) -> Result<bool, Error> {
+        match &node.node_type {
+            NodeType::Leaf(pairs) => {
+                Ok(pairs.len() <= (2 * self.b) && node.size() <= USABLE_PAGE_SIZE)
+            }
+            NodeType::Internal(_, keys) => {
+                Ok(keys.len() <= (2 * self.b - 1) && node.size() <= USABLE_PAGE_SIZE)
+            }
+            NodeType::Unexpected => Err(Error::UnexpectedError),
+        }
+    }
+
+    fn is_node_underflow(&self, node: &Node) -> Result<bool, Error> {
+        match &node.node_type {
+            // A root cannot really be "underflowing" as it can contain less than b-1 keys / pointers.
+            NodeType::Leaf(pairs) => Ok(pairs.len() < (self.b - 1) && !node.is_root),
+            NodeType::Internal(_, keys) => Ok(keys.len() < (self.b - 1) && !node.is_root),
+            NodeType::Unexpected => Err(Error::UnexpectedError),
+        }
+    }
+
+    /// set_root commits a new root by logging it to the wal along with the free pages,
+    /// the commit is flushed to disk as required by the durability policy.
+    pub(crate) fn set_root(&mut self, offset: Offset) -> Result<(), Error> {
+        let record = self.pager.write()?.commit(offset)?;
+        let due = self.durability.is_due(self.last_sync);
+        // The pages of the new root have to be durable before it is logged.
+        if due {
+            self.pager.read()?.sync()?;
+        }
+        self.wal.append(&record)?;
+        // Readers observe the new root once it is logged.
+        self.pager.write()?.publish(record.root.clone());
+        if due {
+            self.wal.sync()?;
+            self.last_sync = Instant::now();
+            self.pager.write()?.set_durable()?;
+        }
+        if self
+            .auto_checkpoint
+            .is_some_and(|wal_size| self.wal.size() > wal_size)
+        {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// checkpoint records the last committed root in the superblock and resets the wal, see BTree::checkpoint.
+    fn checkpoint(&mut self) -> Result<(), Error> {
+        let record = self.wal.get_record()?;
+        self.pager
+            .write()?
+            .set_version(record.version, record.version);
+        // The pages of the root have to be durable before the superblock records it.
+        self.pager.read()?.sync()?;
+        self.superblock.set_record(record.clone());
+        self.write_superblock()?;
+        self.pager.read()?.sync()?;
+        // Until the wal is rewritten the tree is recovered from the root in its superblock.
+        self.wal = Wal::new(self.backend.as_ref(), &self.wal_path, self.superblock.id)?;
+        self.wal.append(&record)?;
+        self.wal.sync()?;
+        self.last_sync = Instant::now();
+        self.pager.write()?.set_durable()?;
+        Ok(())
+    }
+
+    /// sync flushes every commit made so far to disk, see BTree::sync.
+    fn sync(&mut self) -> Result<(), Error> {
+        self.pager.read()?.sync()?;
+        self.wal.sync()?;
+        self.last_sync = Instant::now();
+        self.pager.write()?.set_durable()?;
+        Ok(())
+    }
+
+    /// write_superblock writes the superblock to the tree file along with the oldest readable version.
+    fn write_superblock(&mut self) -> Result<(), Error> {
+        self.superblock.oldest_version = self.pager.read()?.oldest_version();
+        self.pager.write()?.write_page_at_offset(
+            Page::try_from(&self.superblock)?,
+            &Offset(SUPERBLOCK_OFFSET),
+        )
+    }
+
+    /// write_overflow moves the part of a value which does not fit a leaf cell to a chain of overflow pages,
+    /// returning the pair to be stored in the leaf - the key along with a prefix of the value.
+    fn write_overflow(&mut self, kv: KeyValuePair) -> Result<KeyValuePair, Error> {
+        let prefix_len = match overflow_prefix_len(&kv) {
+            Some(prefix_len) => prefix_len,
+            None => return Ok(kv),
+        };
+        let mut next = None;
+        // Write the chain backwards so that each page already knows the offset of its successor.
+        for chunk in kv.value.as_bytes()[prefix_len..]
+            .chunks(OVERFLOW_DATA_SIZE)
+            .rev()
+        {
+            let page = Page::try_from(&OverflowPage::new(next, chunk.to_vec()))?;
+            next = Some(self.pager.write()?.write_page(page)?);
+        }
+        let KeyValuePair { key, mut value, .. } = kv;
+        value.truncate(prefix_len);
+        let mut pair = KeyValuePair::new(key, value);
+        pair.overflow = next;
+        Ok(pair)
+    }
+
+    /// release_overflow releases the overflow pages of a value which is no longer referenced.
+    fn release_overflow(&mut self, kv: &KeyValuePair) -> Result<(), Error> {
+        let mut next = kv.overflow.clone();
+        while let Some(offset) = next {
+            next = OverflowPage::try_from(self.pager.read()?.get_page(&offset)?)?.next;
+            self.pager.write()?.release(offset);
+        }
+        Ok(())
+    }
+
+    /// begin returns the root a transaction starts from.
+    fn begin(&mut self) -> Result<Offset, Error> {
+        self.wal.get_root()
+    }
+
+    /// collect_garbage frees the pages which are not reachable, see BTree::collect_garbage.
+    fn collect_garbage(&mut self) -> Result<usize, Error> {
+        let mut roots = vec![self.wal.get_root()?];
+        roots.extend(self.pager.read()?.pinned_roots()?);
+        let mut reachable = HashSet::new();
+        for root in roots {
+            self.mark_reachable(root, &mut reachable)?;
+        }
+        self.pager.write()?.collect(&reachable)
+    }
+
+    /// compact writes the live pairs of the tree into a new tree file at dest, see BTree::compact.
+    fn compact(&mut self, dest: &Path) -> Result<(), Error> {
+        let dest_wal_path = default_wal_path(dest);
+        for path in [dest, dest_wal_path.as_path()] {
+            if is_same_file(path, &self.path) || is_same_file(path, &self.wal_path) {
+                return Err(Error::InvalidOptions(format!(
+                    "{} is in use by the tree being compacted",
+                    path.display()
+                )));
+            }
+            if self.backend.exists(path) {
+                return Err(Error::TreeAlreadyExists(path.to_path_buf()));
+            }
+        }
+        let snapshot = Snapshot::latest(Arc::clone(&self.pager))?;
+        let backend = self.backend.as_ref();
+        let superblock =
+            compact::write_compacted(&snapshot, self.b, Uuid::new_v4(), backend, dest)?;
+        let mut wal = Wal::new(backend, &dest_wal_path, superblock.id)?;
+        wal.append(&superblock.record())?;
+        wal.sync()
+    }
+
+    /// vacuum compacts the tree in place replacing its pager, see BTree::vacuum.
+    fn vacuum(&mut self) -> Result<(), Error> {
+        // The tree file has to be recoverable on its own before the wal is reset.
+        self.checkpoint()?;
+        let snapshot = Snapshot::latest(Arc::clone(&self.pager))?;
+        let compacted = suffixed_path(&self.path, "-vacuum");
+        let backend = Arc::clone(&self.backend);
+        let superblock = compact::write_compacted(
+            &snapshot,
+            self.b,
+            self.superblock.id,
+            backend.as_ref(),
+            &compacted,
+        )?;
+        drop(snapshot);
+        let record = superblock.record();
+        // The compacted file is locked before it takes the place of the tree file.
+        let mut pager = Pager::open(backend.as_ref(), &compacted)?;
+        pager.set_cache_capacity(self.pager.read()?.cache_capacity()?)?;
+        // Until the compacted file is in place the tree is recovered from the root in its superblock,
+        // thus the wal is reset on disk before the compacted file replaces the tree file.
+        self.wal = Wal::new(backend.as_ref(), &self.wal_path, superblock.id)?;
+        self.wal.sync()?;
+        backend.rename(&compacted, &self.path)?;
+        self.wal.append(&record)?;
+        self.wal.sync()?;
+
+        pager.set_version(1, 1);
+        pager.load_freelist(&record)?;
+        self.pager = Arc::new(RwLock::new(pager));
+        self.superblock = superblock;
+        Ok(())
+    }
+
+    /// mark_reachable adds the pages of the sub tree rooted at the given offset,
+    /// including the overflow pages of its values, to the set of reachable pages.
+    fn mark_reachable(&self, offset: Offset, reachable: &mut HashSet<usize>) -> Result<(), Error> {
+        // Sub trees shared by several roots are only visited once.
+        if !reachable.insert(offset.0) {
+            return Ok(());
+        }
+        match read_node(&self.pager, &offset)?.node_type {
+            NodeType::Internal(children, _) => {
+                for child_offset in children {
+                    self.mark_reachable(child_offset, reachable)?;
+                }
+            }
+            NodeType::Leaf(pairs) => {
+                for pair in pairs {
+                    let mut next = pair.overflow;
+                    while let Some(overflow_offset) = next {
+                        let page = self.pager.read()?.get_page(&overflow_offset)?;
+                        next = OverflowPage::try_from(page)?.next;
+                        reachable.insert(overflow_offset.0);
+                    }
+                }
+            }
+            NodeType::Unexpected => return Err(Error::UnexpectedError),
+        }
+        Ok(())
+    }
+
+    /// rollback discards every page written since the last committed root.
+    pub(crate) fn rollback(&mut self) {
+        if let Ok(mut pager) = self.pager.write() {
+            pager.rollback();
+        }
+    }
+
+    /// shadow writes a copy of the node found at the given offset as part of a copy-on-write traversal.
+    /// A page written since the last commit is not referenced by the committed tree and is thus overwritten in place.
+    fn shadow(&mut self, offset: &Offset, node: &Node) -> Result<Offset, Error> {
+        let page = Page::try_from(node)?;
+        if self.pager.write()?.is_dirty(offset) {
+            self.pager.write()?.write_page_at_offset(page, offset)?;
+            return Ok(offset.clone());
+        }
+        self.pager.write()?.write_page(page)
+    }
+
+    /// check_write fails if a key value pair cannot be written to the tree rooted at the given offset
+    /// according to the given mode, e.g. with KeyAlreadyExists. Checked before write_at copies any page.
+    pub(crate) fn check_write(
+        &self,
+        root_offset: &Offset,
+        kv: &KeyValuePair,
+        mode: WriteMode,
+    ) -> Result<(), Error> {
+        if kv.key.len() > MAX_KEY_SIZE {
+            return Err(Error::KeyOverflowError);
+        }
+        if mode != WriteMode::Upsert {
+            let root = read_node(&self.pager, root_offset)?;
+            match (search_node(&self.pager, root, &kv.key), mode) {
+                (Ok(_), WriteMode::Insert) => return Err(Error::KeyAlreadyExists),
+                (Err(Error::KeyNotFound), WriteMode::Update) => return Err(Error::KeyNotFound),
+                (Ok(_), _) | (Err(Error::KeyNotFound), _) => {}
+                (Err(e), _) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// write_at writes a key value pair to the tree rooted at the given offset, replacing the value of an existing key,
+    /// returning the offset of the new root along with the value it replaced. The write is expected
+    /// to pass check_write, pages written by the transaction are overwritten in place along the way.
+    pub(crate) fn write_at(
+        &mut self,
+        root_offset: &Offset,
+        kv: KeyValuePair,
+    ) -> Result<(Offset, Option<String>), Error> {
+        let kv = self.write_overflow(kv)?;
+        let root_page = self.pager.read()?.get_page(root_offset)?;
+        let new_root_offset: Offset;
+        let mut new_root: Node;
+        let mut root = Node::try_from(root_page)?;
+        if self.is_node_full(&root, &kv)? {
+            // split the root creating a new root and child nodes along the way.
+            new_root = Node::new(NodeType::Internal(vec![], vec![]), true, None);
+            // write the new root to disk to aquire an offset for the new root.
+            new_root_offset = self.pager.write()?.write_page(Page::try_from(&new_root)?)?;
+            // set the old roots parent to the new root.
+            root.parent_offset = Some(new_root_offset.clone());
+            root.is_root = false;
+            // split the old root.
+            let (median, sibling) = root.split(root.split_index(self.b)?)?;
+            // write the old root with its new data to disk in a *new* location.
+            let old_root_offset = self.shadow(root_offset, &root)?;
+            // write the newly created sibling to disk.
+            let sibling_offset = self.pager.write()?.write_page(Page::try_from(&sibling)?)?;
+            // update the new root with its children and key.
+            new_root.node_type =
+                NodeType::Internal(vec![old_root_offset, sibling_offset], vec![median]);
+            // write the new_root to disk.
+            self.pager
+                .write()?
+                .write_page_at_offset(Page::try_from(&new_root)?, &new_root_offset)?;
+        } else {
+            new_root = root.clone();
+            new_root_offset = self.shadow(root_offset, &new_root)?;
+        }
+        // continue recursively.
+        let previous = self.insert_non_full(&mut new_root, new_root_offset.clone(), kv)?;
+        Ok((new_root_offset, previous))
+    }
+
+    /// insert_non_full (recursively) finds a node rooted at a given non-full node.
+    /// to insert a given key-value pair, replacing the pair of an existing key and returning its value.
+    /// Here we assume the node is already a copy of an existing node in a copy-on-write root to node traversal.
+    fn insert_non_full(
+        &mut self,
+        node: &mut Node,
+        node_offset: Offset,
+        kv: KeyValuePair,
+    ) -> Result<Option<String>, Error> {
+        match &mut node.node_type {
+            NodeType::Leaf(ref mut pairs) => {
+                let previous = match pairs.binary_search(&kv) {
+                    Ok(idx) => Some(std::mem::replace(&mut pairs[idx], kv)),
+                    Err(idx) => {
+                        pairs.insert(idx, kv);
+                        None
+                    }
+                };
+                self.pager
+                    .write()?
+                    .write_page_at_offset(Page::try_from(&*node)?, &node_offset)?;
+                match previous {
+                    Some(previous) => {
+                        // The replaced value's overflow pages are no longer needed once the new root is committed.
+                        let value = read_overflow(&self.pager, previous.clone())?.value;
+                        self.release_overflow(&previous)?;
+                        Ok(Some(value))
+                    }
+                    None => Ok(None),
+                }
+            }
+            NodeType::Internal(ref mut children, ref mut keys) => {
+                let idx = keys
+                    .binary_search(&Key(kv.key.clone()))
+                    .unwrap_or_else(|x| x);
+                let child_offset = children.get(idx).ok_or(Error::UnexpectedError)?.clone();
+                let child_page = self.pager.read()?.get_page(&child_offset)?;
+                let mut child = Node::try_from(child_page)?;
+                // Copy each branching-node on the root-to-leaf walk.
+                // shadow appends the given page to the db file thus creating a new node.
+                let new_child_offset = self.shadow(&child_offset, &child)?;
+                // Assign copied child at the proper place.
+                children[idx] = new_child_offset.to_owned();
+                if self.is_node_full(&child, &kv)? {
+                    // split will split the child in two halves of about the same size,
+                    // for equally sized keys this leaves the [0, b-1] keys
+                    // while moving the set of [b, 2b-1] keys to the sibling.
+                    let (median, mut sibling) = child.split(child.split_index(self.b)?)?;
+                    self.pager
+                        .write()?
+                        .write_page_at_offset(Page::try_from(&child)?, &new_child_offset)?;
+                    // Write the newly created sibling to disk.
+                    let sibling_offset =
+                        self.pager.write()?.write_page(Page::try_from(&sibling)?)?;
+                    // Siblings keys are larger than the splitted child thus need to be inserted
+                    // at the next index.
+                    children.insert(idx + 1, sibling_offset.clone());
+                    keys.insert(idx, median.clone());
+
+                    // Write the parent page to disk.
+                    self.pager
+                        .write()?
+                        .write_page_at_offset(Page::try_from(&*node)?, &node_offset)?;
+                    // Continue recursively.
+                    if kv.key <= median.0 {
+                        self.insert_non_full(&mut child, new_child_offset, kv)
+                    } else {
+                        self.insert_non_full(&mut sibling, sibling_offset, kv)
+                    }
+                } else {
+                    self.pager
+                        .write()?
+                        .write_page_at_offset(Page::try_from(&*node)?, &node_offset)?;
+                    self.insert_non_full(&mut child, new_child_offset, kv)
+                }
+            }
+            NodeType::Unexpected => Err(Error::UnexpectedError),
+        }
+    }
+
+    /// search_at searches for a specific key in the tree rooted at the given offset.
+    pub(crate) fn search_at(&self, root_offset: &Offset, key: &str) -> Result<KeyValuePair, Error> {
+        search_tree(&self.pager, root_offset, key)
+    }
+
+    /// check_delete fails with KeyNotFound if the key is not in the tree rooted at the given offset.
+    /// Checked before delete_at copies any page.
+    pub(crate) fn check_delete(&self, root_offset: &Offset, key: &Key) -> Result<(), Error> {
+        let root = read_node(&self.pager, root_offset)?;
+        search_node(&self.pager, root, &key.0)?;
+        Ok(())
+    }
+
+    /// delete_at deletes a given key from the tree rooted at the given offset, returning the offset of the new root.
+    /// The key is expected to pass check_delete.
+    pub(crate) fn delete_at(&mut self, root_offset: &Offset, key: Key) -> Result<Offset, Error> {
+        let root_page = self.pager.read()?.get_page(root_offset)?;
+        let mut new_root = Node::try_from(root_page)?;
+        // Shadow the new root and rewrite it.
+        let new_root_offset = self.shadow(root_offset, &new_root)?;
+        let collapsed_root = self.delete_key_from_subtree(key, &mut new_root, &new_root_offset)?;
+        Ok(collapsed_root.unwrap_or(new_root_offset))
+    }
+
+    /// delete key from subtree recursively traverses a tree rooted at a node in certain offset
+    /// until it finds the given key and delete the key-value pair. Here we assume the node is
+    /// already a copy of an existing node in a copy-on-write root to node traversal.
+    /// Returns the offset of the new root if merges collapsed the root.
+    fn delete_key_from_subtree(
+        &mut self,
+        key: Key,
+        node: &mut Node,
+        node_offset: &Offset,
+    ) -> Result<Option<Offset>, Error> {
+        match &mut node.node_type {
+            NodeType::Leaf(ref mut pairs) => {
+                let key_idx = pairs
+                    .binary_search_by_key(&key, |kv| Key(kv.key.clone()))
+                    .map_err(|_| Error::KeyNotFound)?;
+                let removed = pairs.remove(key_idx);
+                // The value's overflow pages are no longer needed once the new root is committed.
+                self.release_overflow(&removed)?;
+                self.pager
+                    .write()?
+                    .write_page_at_offset(Page::try_from(&*node)?, node_offset)?;
+                // Check for underflow - if it occures,
+                // we need to merge with a sibling.
                 // this can only occur if node is not the root (as it cannot "underflow").
                 // continue recoursively up the tree.
-                self.borrow_if_needed(node.to_owned(), &key)?;
+                self.borrow_if_needed(node.to_owned(), &key)
             }
             NodeType::Internal(children, keys) => {
                 let node_idx = keys.binary_search(&key).unwrap_or_else(|x| x);
                 // Retrieve child page from disk and deserialize,
                 // copy over the child page and continue recursively.
-                let child_offset = children.get(node_idx).ok_or(Error::UnexpectedError)?;
-                let child_page = self.pager.get_page(child_offset)?;
+                let child_offset = children
+                    .get(node_idx)
+                    .ok_or(Error::UnexpectedError)?
+                    .clone();
+                let child_page = self.pager.read()?.get_page(&child_offset)?;
                 let mut child_node = Node::try_from(child_page)?;
                 // Fix the parent_offset as the child node is a child of a copied parent
                 // in a copy-on-write root to leaf traversal.
                 // This is important for the case of a node underflow which might require a leaf to root traversal.
                 child_node.parent_offset = Some(node_offset.to_owned());
-                let new_child_page = Page::try_from(&child_node)?;
-                let new_child_offset = self.pager.write_page(new_child_page)?;
+                let new_child_offset = self.shadow(&child_offset, &child_node)?;
                 // Assign the new pointer in the parent and continue reccoursively.
                 children[node_idx] = new_child_offset.to_owned();
                 self.pager
+                    .write()?
                     .write_page_at_offset(Page::try_from(&*node)?, node_offset)?;
-                return self.delete_key_from_subtree(key, &mut child_node, &new_child_offset);
+                self.delete_key_from_subtree(key, &mut child_node, &new_child_offset)
             }
-            NodeType::Unexpected => return Err(Error::UnexpectedError),
+            NodeType::Unexpected => Err(Error::UnexpectedError),
         }
-        Ok(())
     }
 
     /// borrow_if_needed checks the node for underflow (following a removal of a key),
     /// if it underflows it is merged with a sibling node, and than called recoursively
     /// up the tree. Since the downward root-to-leaf traversal was done using the copy-on-write
     /// technique we are ensured that any merges will only be reflected in the copied parent in the path.
-    fn borrow_if_needed(&mut self, node: Node, key: &Key) -> Result<(), Error> {
+    /// Returns the offset of the new root if the root was replaced by the merged node.
+    fn borrow_if_needed(&mut self, node: Node, key: &Key) -> Result<Option<Offset>, Error> {
         if self.is_node_underflow(&node)? {
             // Fetch the sibling from the parent -
             // TODO: This could be quicker if we implement sibling pointers.
             let parent_offset = node.parent_offset.clone().ok_or(Error::UnexpectedError)?;
-            let parent_page = self.pager.get_page(&parent_offset)?;
+            let parent_page = self.pager.read()?.get_page(&parent_offset)?;
             let mut parent_node = Node::try_from(parent_page)?;
             // The parent has to be an "internal" node.
             match parent_node.node_type {
@@ -305,56 +1270,88 @@ impl BTree {
                     let idx = keys.binary_search(key).unwrap_or_else(|x| x);
                     // The sibling is in idx +- 1 as the above index led
                     // the downward search to node.
-                    let sibling_idx;
-                    match idx > 0 {
-                        false => sibling_idx = idx + 1,
-                        true => sibling_idx = idx - 1,
-                    }
+                    let sibling_idx = match idx > 0 {
+                        false => idx + 1,
+                        true => idx - 1,
+                    };
 
                     let sibling_offset = children.get(sibling_idx).ok_or(Error::UnexpectedError)?;
-                    let sibling_page = self.pager.get_page(sibling_offset)?;
+                    let sibling_page = self.pager.read()?.get_page(sibling_offset)?;
                     let sibling = Node::try_from(sibling_page)?;
-                    let merged_node = self.merge(node, sibling)?;
-                    let merged_node_offset =
-                        self.pager.write_page(Page::try_from(&merged_node)?)?;
                     let merged_node_idx = cmp::min(idx, sibling_idx);
+                    // The key separating the two nodes is pulled down into a merged internal node.
+                    let separator = keys
+                        .get(merged_node_idx)
+                        .ok_or(Error::UnexpectedError)?
+                        .clone();
+                    let mut merged_node = match sibling_idx < idx {
+                        true => self.merge(sibling, node, separator)?,
+                        false => self.merge(node, sibling, separator)?,
+                    };
+                    // The two nodes might not fit a single node once merged,
+                    // in that case the node borrows from its sibling by splitting the merged node evenly.
+                    if !self.fits(&merged_node)? {
+                        merged_node.parent_offset = Some(parent_offset.clone());
+                        let (median, sibling) =
+                            merged_node.split(merged_node.split_index(self.b)?)?;
+                        children[merged_node_idx] = self
+                            .pager
+                            .write()?
+                            .write_page(Page::try_from(&merged_node)?)?;
+                        children[merged_node_idx + 1] =
+                            self.pager.write()?.write_page(Page::try_from(&sibling)?)?;
+                        keys[merged_node_idx] = median;
+                        // The parent keeps its number of keys thus there is no need to continue up the tree.
+                        self.pager
+                            .write()?
+                            .write_page_at_offset(Page::try_from(&parent_node)?, &parent_offset)?;
+                        return Ok(None);
+                    }
+                    // if the parent is the root, and there is a single child - the merged node -
+                    // we can safely replace the root with the child.
+                    if parent_node.is_root && children.len() == 2 {
+                        merged_node.is_root = true;
+                        merged_node.parent_offset = None;
+                        let merged_node_offset = self
+                            .pager
+                            .write()?
+                            .write_page(Page::try_from(&merged_node)?)?;
+                        return Ok(Some(merged_node_offset));
+                    }
+                    merged_node.parent_offset = Some(parent_offset.clone());
+                    let merged_node_offset = self
+                        .pager
+                        .write()?
+                        .write_page(Page::try_from(&merged_node)?)?;
                     // remove the old nodes.
                     children.remove(merged_node_idx);
                     // remove shifts nodes to the left.
                     children.remove(merged_node_idx);
-                    // if the parent is the root, and there is a single child - the merged node -
-                    // we can safely replace the root with the child.
-                    if parent_node.is_root && children.is_empty() {
-                        self.wal.set_root(merged_node_offset)?;
-                        return Ok(());
-                    }
-                    // remove the keys that separated the two nodes from each other:
-                    keys.remove(idx);
+                    // remove the key that separated the two nodes from each other:
+                    keys.remove(merged_node_idx);
                     // write the new node in place.
                     children.insert(merged_node_idx, merged_node_offset);
                     // write the updated parent back to disk and continue up the tree.
                     self.pager
+                        .write()?
                         .write_page_at_offset(Page::try_from(&parent_node)?, &parent_offset)?;
                     return self.borrow_if_needed(parent_node, key);
                 }
                 _ => return Err(Error::UnexpectedError),
             }
         }
-        Ok(())
+        Ok(None)
     }
 
-    // merges two *sibling* nodes, it assumes the following:
+    // merges two *sibling* nodes given in order along with the key separating them, it assumes the following:
     // 1. the two nodes are of the same type.
-    // 2. the two nodes do not accumulate to an overflow,
-    // i.e. |first.keys| + |second.keys| <= [2*(b-1) for keys or 2*b for offsets].
-    fn merge(&self, first: Node, second: Node) -> Result<Node, Error> {
+    // 2. the two nodes do not accumulate to an overflow which is verified by the caller using `fits`.
+    fn merge(&self, first: Node, second: Node, separator: Key) -> Result<Node, Error> {
         match first.node_type {
             NodeType::Leaf(first_pairs) => {
                 if let NodeType::Leaf(second_pairs) = second.node_type {
-                    let merged_pairs: Vec<KeyValuePair> = first_pairs
-                        .into_iter()
-                        .chain(second_pairs.into_iter())
-                        .collect();
+                    let merged_pairs: Vec<KeyValuePair> =
+                        first_pairs.into_iter().chain(second_pairs).collect();
                     let node_type = NodeType::Leaf(merged_pairs);
                     Ok(Node::new(node_type, first.is_root, first.parent_offset))
                 } else {
@@ -365,12 +1362,11 @@ impl BTree {
                 if let NodeType::Internal(second_offsets, second_keys) = second.node_type {
                     let merged_keys: Vec<Key> = first_keys
                         .into_iter()
-                        .chain(second_keys.into_iter())
-                        .collect();
-                    let merged_offsets: Vec<Offset> = first_offsets
-                        .into_iter()
-                        .chain(second_offsets.into_iter())
+                        .chain(std::iter::once(separator))
+                        .chain(second_keys)
                         .collect();
+                    let merged_offsets: Vec<Offset> =
+                        first_offsets.into_iter().chain(second_offsets).collect();
                     let node_type = NodeType::Internal(merged_offsets, merged_keys);
                     Ok(Node::new(node_type, first.is_root, first.parent_offset))
                 } else {
@@ -380,37 +1376,6 @@ impl BTree {
             NodeType::Unexpected => Err(Error::UnexpectedError),
         }
     }
-
-    /// print_sub_tree is a helper function for recursively printing the nodes rooted at a node given by its offset.
-    fn print_sub_tree(&mut self, prefix: String, offset: Offset) -> Result<(), Error> {
-        println!("{}Node at offset: {}", prefix, offset.0);
-        let curr_prefix = format!("{}|->", prefix);
-        let page = self.pager.get_page(&offset)?;
-        let node = Node::try_from(page)?;
-        match node.node_type {
-            NodeType::Internal(children, keys) => {
-                println!("{}Keys: {:?}", curr_prefix, keys);
-                println!("{}Children: {:?}", curr_prefix, children);
-                let child_prefix = format!("{}   |  ", prefix);
-                for child_offset in children {
-                    self.print_sub_tree(child_prefix.clone(), child_offset)?;
-                }
-                Ok(())
-            }
-            NodeType::Leaf(pairs) => {
-                println!("{}Key value pairs: {:?}", curr_prefix, pairs);
-                Ok(())
-            }
-            NodeType::Unexpected => Err(Error::UnexpectedError),
-        }
-    }
-
-    /// print is a helper for recursively printing the tree.
-    pub fn print(&mut self) -> Result<(), Error> {
-        println!();
-        let root_offset = self.wal.get_root()?;
-        self.print_sub_tree("".to_string(), root_offset)
-    }
 }
 
 #[cfg(test)]
@@ -421,10 +1386,12 @@ mod tests {
     fn search_works() -> Result<(), Error> {
         use crate::btree::BTreeBuilder;
         use crate::node_type::KeyValuePair;
+        use std::fs;
         use std::path::Path;
 
-        let mut btree = BTreeBuilder::new()
-            .path(Path::new("/tmp/db"))
+        fs::create_dir_all("/tmp/btree_search")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_search/db"))
             .b_parameter(2)
             .build()?;
         btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
@@ -446,10 +1413,12 @@ mod tests {
     fn insert_works() -> Result<(), Error> {
         use crate::btree::BTreeBuilder;
         use crate::node_type::KeyValuePair;
+        use std::fs;
         use std::path::Path;
 
-        let mut btree = BTreeBuilder::new()
-            .path(Path::new("/tmp/db"))
+        fs::create_dir_all("/tmp/btree_insert")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_insert/db"))
             .b_parameter(2)
             .build()?;
         btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
@@ -505,10 +1474,12 @@ mod tests {
         use crate::btree::BTreeBuilder;
         use crate::error::Error;
         use crate::node_type::{Key, KeyValuePair};
+        use std::fs;
         use std::path::Path;
 
-        let mut btree = BTreeBuilder::new()
-            .path(Path::new("/tmp/db"))
+        fs::create_dir_all("/tmp/btree_delete")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_delete/db"))
             .b_parameter(2)
             .build()?;
         btree.insert(KeyValuePair::new("d".to_string(), "olah".to_string()))?;
@@ -549,10 +1520,12 @@ mod tests {
     fn delete_with_empty_sub_tree() -> Result<(), Error> {
         use crate::btree::BTreeBuilder;
         use crate::node_type::{Key, KeyValuePair};
+        use std::fs;
         use std::path::Path;
 
-        let mut btree = BTreeBuilder::new()
-            .path(Path::new("/tmp/db"))
+        fs::create_dir_all("/tmp/btree_delete_empty")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_delete_empty/db"))
             .b_parameter(2)
             .build()?;
         btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
@@ -576,11 +1549,11 @@ mod tests {
         btree.delete(Key("a".to_string()))?;
         res = btree.search("a".to_string());
         assert!(matches!(res, Err(Error::KeyNotFound)));
-        
+
         btree.delete(Key("b".to_string()))?;
         res = btree.search("b".to_string());
         assert!(matches!(res, Err(Error::KeyNotFound)));
-        
+
         btree.delete(Key("c".to_string()))?;
         res = btree.search("c".to_string());
         assert!(matches!(res, Err(Error::KeyNotFound)));
@@ -594,4 +1567,1611 @@ mod tests {
         assert!(matches!(res, Err(Error::KeyNotFound)));
         Ok(())
     }
+
+    #[test]
+    fn open_recovers_existing_tree() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_reopen")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_reopen/db"))
+            .b_parameter(2)
+            .build()?;
+        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+        btree.insert(KeyValuePair::new("b".to_string(), "hello".to_string()))?;
+        btree.insert(KeyValuePair::new("c".to_string(), "marhaba".to_string()))?;
+        btree.insert(KeyValuePair::new("d".to_string(), "olah".to_string()))?;
+        btree.insert(KeyValuePair::new("e".to_string(), "salam".to_string()))?;
+        drop(btree);
+
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_reopen/db"))
+            .b_parameter(2)
+            .open()?;
+        let kv = btree.search("d".to_string())?;
+        assert_eq!(kv.value, "olah");
+
+        // New pages must be appended after the recovered ones.
+        btree.insert(KeyValuePair::new("f".to_string(), "hallo".to_string()))?;
+        let kv = btree.search("a".to_string())?;
+        assert_eq!(kv.value, "shalom");
+        let kv = btree.search("f".to_string())?;
+        assert_eq!(kv.value, "hallo");
+        Ok(())
+    }
+
+    #[test]
+    fn durability_policies_preserve_commits() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::durability::Durability;
+        use crate::node_type::KeyValuePair;
+        use std::fs;
+        use std::path::Path;
+        use std::time::Duration;
+
+        fs::create_dir_all("/tmp/btree_durability")?;
+        for durability in [
+            Durability::None,
+            Durability::Commit,
+            Durability::Periodic(Duration::from_millis(1)),
+        ] {
+            let btree = BTreeBuilder::new()
+                .path(Path::new("/tmp/btree_durability/db"))
+                .b_parameter(2)
+                .durability(durability)
+                .build()?;
+            for i in 0..20 {
+                btree.insert(KeyValuePair::new(format!("{:02}", i), i.to_string()))?;
+            }
+            btree.sync()?;
+            drop(btree);
+
+            let btree = BTreeBuilder::new()
+                .path(Path::new("/tmp/btree_durability/db"))
+                .b_parameter(2)
+                .open()?;
+            assert_eq!(btree.iter()?.count(), 20);
+            assert_eq!(btree.search("13".to_string())?.value, "13");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unsynced_commits_do_not_reuse_durable_pages() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::durability::Durability;
+        use crate::node_type::KeyValuePair;
+        use crate::page_layout::PAGE_SIZE;
+        use crate::storage::{Access, Backend, MemoryBackend, PageStore, Storage};
+        use std::collections::HashMap;
+        use std::path::{Path, PathBuf};
+        use std::sync::{Arc, Mutex};
+
+        type Synced = Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>;
+
+        /// CrashBackend keeps the bytes of each wal as of its last sync,
+        /// a crash reverts a wal to them losing the records appended since.
+        #[derive(Clone, Default)]
+        struct CrashBackend {
+            inner: MemoryBackend,
+            synced: Synced,
+        }
+        struct CrashStorage {
+            inner: Box<dyn Storage>,
+            path: PathBuf,
+            synced: Synced,
+        }
+        impl CrashBackend {
+            fn crash(&self, path: &Path) -> Result<(), Error> {
+                let bytes = self.synced.lock()?.get(path).cloned().unwrap_or_default();
+                let mut storage = self.inner.open_log(path, Access::ReadWrite)?;
+                storage.set_size(0)?;
+                storage.write_at(&bytes, 0)
+            }
+        }
+        impl Backend for CrashBackend {
+            fn open_pages(&self, path: &Path, access: Access) -> Result<Box<dyn PageStore>, Error> {
+                self.inner.open_pages(path, access)
+            }
+            fn open_log(&self, path: &Path, access: Access) -> Result<Box<dyn Storage>, Error> {
+                Ok(Box::new(CrashStorage {
+                    inner: self.inner.open_log(path, access)?,
+                    path: path.to_path_buf(),
+                    synced: Arc::clone(&self.synced),
+                }))
+            }
+            fn exists(&self, path: &Path) -> bool {
+                self.inner.exists(path)
+            }
+            fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+                self.inner.rename(from, to)?;
+                let mut synced = self.synced.lock()?;
+                if let Some(bytes) = synced.remove(from) {
+                    synced.insert(to.to_path_buf(), bytes);
+                }
+                Ok(())
+            }
+        }
+        impl Storage for CrashStorage {
+            fn read_at(&self, buf: &mut [u8], offset: usize) -> Result<(), Error> {
+                self.inner.read_at(buf, offset)
+            }
+            fn write_at(&mut self, buf: &[u8], offset: usize) -> Result<(), Error> {
+                self.inner.write_at(buf, offset)
+            }
+            fn size(&self) -> Result<usize, Error> {
+                self.inner.size()
+            }
+            fn set_size(&mut self, size: usize) -> Result<(), Error> {
+                self.inner.set_size(size)
+            }
+            fn sync(&self) -> Result<(), Error> {
+                let mut bytes = vec![0x00; self.inner.size()?];
+                self.inner.read_at(&mut bytes, 0)?;
+                self.synced.lock()?.insert(self.path.clone(), bytes);
+                self.inner.sync()
+            }
+            fn try_lock(&self, shared: bool) -> Result<bool, Error> {
+                self.inner.try_lock(shared)
+            }
+        }
+
+        let backend = CrashBackend::default();
+        let btree = BTreeBuilder::new()
+            .path("db")
+            .b_parameter(2)
+            .durability(Durability::None)
+            .backend(backend.clone())
+            .build()?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(
+                format!("{:02}", i),
+                "synced".repeat(PAGE_SIZE),
+            ))?;
+        }
+        btree.sync()?;
+        // The overflow pages released by each upsert are reused by the following ones.
+        for i in 0..20 {
+            btree.upsert(KeyValuePair::new(
+                format!("{:02}", i),
+                "lost".repeat(PAGE_SIZE),
+            ))?;
+        }
+        drop(btree);
+
+        // The pages written since the sync reached the disk while the wal records logging them did not.
+        backend.crash(Path::new("db-wal"))?;
+        let btree = BTreeBuilder::new()
+            .path("db")
+            .b_parameter(2)
+            .backend(backend)
+            .open()?;
+        assert_eq!(btree.iter()?.count(), 20);
+        for kv in btree.iter()? {
+            assert_eq!(kv?.value, "synced".repeat(PAGE_SIZE));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn checkpoint_resets_wal() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_checkpoint")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_checkpoint/db"))
+            .b_parameter(2)
+            .auto_checkpoint(None)
+            .build()?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), i.to_string()))?;
+        }
+        let wal_len = fs::metadata("/tmp/btree_checkpoint/db-wal")?.len();
+        let snapshot = btree.snapshot()?;
+        btree.checkpoint()?;
+        // Only the last of the 21 commits is left in the wal.
+        let checkpointed_len = fs::metadata("/tmp/btree_checkpoint/db-wal")?.len();
+        let record_len = (wal_len - checkpointed_len) / 20;
+        assert!(checkpointed_len < wal_len / 10);
+        assert_eq!(btree.versions()?, 21..=21);
+
+        // Versions keep counting up, the snapshot taken before the checkpoint is still readable.
+        btree.delete(Key("05".to_string()))?;
+        btree.collect_garbage()?;
+        assert_eq!(btree.versions()?, 21..=22);
+        assert_eq!(snapshot.search("05".to_string())?.value, "5");
+        drop(snapshot);
+        drop(btree);
+
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_checkpoint/db"))
+            .b_parameter(2)
+            .auto_checkpoint(Some((checkpointed_len + 10 * record_len) as usize))
+            .open()?;
+        assert_eq!(btree.versions()?, 21..=22);
+        assert_eq!(btree.iter()?.count(), 19);
+
+        // The wal is checkpointed once it grows past ten commits.
+        for i in 20..60 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), i.to_string()))?;
+        }
+        assert!(
+            fs::metadata("/tmp/btree_checkpoint/db-wal")?.len()
+                <= checkpointed_len + 10 * record_len
+        );
+        assert_eq!(*btree.versions()?.end(), 62);
+        assert_eq!(btree.iter()?.count(), 59);
+        Ok(())
+    }
+
+    #[test]
+    fn each_tree_has_its_own_wal() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::error::Error;
+        use crate::node_type::KeyValuePair;
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_wal_path/logs")?;
+        let a = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_wal_path/a.db"))
+            .b_parameter(2)
+            .build()?;
+        let b = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_wal_path/b.db"))
+            .b_parameter(2)
+            .wal_path(Path::new("/tmp/btree_wal_path/logs/b.wal"))
+            .build()?;
+        a.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+        b.insert(KeyValuePair::new("b".to_string(), "hello".to_string()))?;
+        // A tree may be compacted next to itself.
+        let _ = fs::remove_file("/tmp/btree_wal_path/c.db");
+        let _ = fs::remove_file("/tmp/btree_wal_path/c.db-wal");
+        a.compact(Path::new("/tmp/btree_wal_path/c.db"))?;
+        assert!(matches!(
+            a.compact(Path::new("/tmp/btree_wal_path/c.db")),
+            Err(Error::TreeAlreadyExists(_))
+        ));
+        assert!(matches!(
+            a.compact(Path::new("/tmp/btree_wal_path/a.db")),
+            Err(Error::InvalidOptions(_))
+        ));
+        assert!(matches!(
+            a.compact(Path::new("/tmp/btree_wal_path/a.db-wal")),
+            Err(Error::InvalidOptions(_))
+        ));
+        drop(a);
+        drop(b);
+
+        let a = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_wal_path/a.db"))
+            .b_parameter(2)
+            .open()?;
+        assert_eq!(a.search("a".to_string())?.value, "shalom");
+        let b = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_wal_path/b.db"))
+            .b_parameter(2)
+            .wal_path(Path::new("/tmp/btree_wal_path/logs/b.wal"))
+            .open()?;
+        assert_eq!(b.search("b".to_string())?.value, "hello");
+        let c = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_wal_path/c.db"))
+            .b_parameter(2)
+            .open()?;
+        assert_eq!(c.search("a".to_string())?.value, "shalom");
+        drop(a);
+        drop(b);
+
+        // The wal of another tree is refused.
+        fs::copy(
+            "/tmp/btree_wal_path/logs/b.wal",
+            "/tmp/btree_wal_path/a.db-wal",
+        )?;
+        let res = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_wal_path/a.db"))
+            .b_parameter(2)
+            .open();
+        assert!(matches!(res, Err(Error::InvalidHeader(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn max_b_parameter_fits_page() -> Result<(), Error> {
+        use crate::btree::{max_b_parameter, BTreeBuilder, MAX_BRANCHING_FACTOR};
+        use crate::node_type::KeyValuePair;
+        use crate::page_layout::{
+            internal_cell_size, leaf_cell_size, INTERNAL_NODE_HEADER_SIZE, LEAF_NODE_HEADER_SIZE,
+            MAX_CELL_SIZE, PTR_SIZE, USABLE_PAGE_SIZE,
+        };
+        use std::cmp;
+        use std::fs;
+
+        for (key_size, value_size) in [(0, 0), (8, 8), (16, 100), (200, 10000)] {
+            let b = max_b_parameter(key_size, value_size);
+            // Values which do not fit a cell are moved to overflow pages.
+            let leaf_cell = cmp::min(leaf_cell_size(key_size, value_size), MAX_CELL_SIZE);
+            let leaf = LEAF_NODE_HEADER_SIZE + 2 * b * leaf_cell;
+            let internal = INTERNAL_NODE_HEADER_SIZE
+                + 2 * b * PTR_SIZE
+                + (2 * b - 1) * internal_cell_size(key_size);
+            assert!(internal <= USABLE_PAGE_SIZE);
+            assert!(leaf <= USABLE_PAGE_SIZE);
+            assert!(b >= 2);
+        }
+        assert!(max_b_parameter(16, 100) < MAX_BRANCHING_FACTOR);
+
+        fs::create_dir_all("/tmp/btree_max_b")?;
+        let btree = BTreeBuilder::new()
+            .path("/tmp/btree_max_b/db")
+            .b_parameter(MAX_BRANCHING_FACTOR)
+            .build()?;
+        for i in 0..4 * MAX_BRANCHING_FACTOR {
+            btree.insert(KeyValuePair::new(format!("{:04}", i), String::new()))?;
+        }
+        assert_eq!(btree.iter()?.count(), 4 * MAX_BRANCHING_FACTOR);
+        Ok(())
+    }
+
+    #[test]
+    fn builder_validates_options() -> Result<(), Error> {
+        use crate::btree::{BTreeBuilder, MAX_BRANCHING_FACTOR};
+        use crate::error::Error;
+        use crate::node_type::KeyValuePair;
+        use std::fs;
+        use std::path::PathBuf;
+
+        fs::create_dir_all("/tmp/btree_options")?;
+        let _ = fs::remove_file("/tmp/btree_options/db");
+        // Paths may be built at runtime.
+        let path = PathBuf::from(String::from("/tmp/btree_options")).join("db");
+        for b in [0, 1, MAX_BRANCHING_FACTOR + 1] {
+            let res = BTreeBuilder::new().path(&path).b_parameter(b).build();
+            assert!(matches!(res, Err(Error::InvalidOptions(_))));
+        }
+        let res = BTreeBuilder::new().b_parameter(2).build();
+        assert!(matches!(res, Err(Error::InvalidOptions(_))));
+
+        let res = BTreeBuilder::new()
+            .path(&path)
+            .b_parameter(2)
+            .create_if_missing(false)
+            .open();
+        assert!(matches!(res, Err(Error::TreeNotFound(_))));
+        let res = BTreeBuilder::new()
+            .path(&path)
+            .b_parameter(2)
+            .read_only(true)
+            .open();
+        assert!(matches!(res, Err(Error::TreeNotFound(_))));
+
+        let btree = BTreeBuilder::new()
+            .path(path.clone())
+            .b_parameter(2)
+            .error_if_exists(true)
+            .build()?;
+        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+        drop(btree);
+        let res = BTreeBuilder::new()
+            .path(&path)
+            .b_parameter(2)
+            .error_if_exists(true)
+            .open();
+        assert!(matches!(res, Err(Error::TreeAlreadyExists(_))));
+        let res = BTreeBuilder::new()
+            .path(&path)
+            .b_parameter(2)
+            .read_only(true)
+            .build();
+        assert!(matches!(res, Err(Error::InvalidOptions(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_tree_is_never_written() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::error::Error;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_read_only")?;
+        let btree = BTreeBuilder::new()
+            .path("/tmp/btree_read_only/db")
+            .b_parameter(2)
+            .build()?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), i.to_string()))?;
+        }
+        drop(btree);
+        let tree_file = fs::read("/tmp/btree_read_only/db")?;
+        let wal = fs::read("/tmp/btree_read_only/db-wal")?;
+
+        let btree = BTreeBuilder::new()
+            .path("/tmp/btree_read_only/db")
+            .b_parameter(2)
+            .read_only(true)
+            .open()?;
+        assert_eq!(btree.search("07".to_string())?.value, "7");
+        assert_eq!(btree.iter()?.count(), 20);
+        assert_eq!(btree.snapshot()?.iter().count(), 20);
+        assert!(matches!(
+            btree.insert(KeyValuePair::new("20".to_string(), "20".to_string())),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(
+            btree.delete(Key("07".to_string())),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(btree.begin(), Err(Error::ReadOnly)));
+        assert!(matches!(btree.collect_garbage(), Err(Error::ReadOnly)));
+        assert!(matches!(btree.checkpoint(), Err(Error::ReadOnly)));
+        assert!(matches!(btree.vacuum(), Err(Error::ReadOnly)));
+        btree.sync()?;
+        // A read only tree can still be copied elsewhere.
+        let _ = fs::remove_file("/tmp/btree_read_only/copy");
+        let _ = fs::remove_file("/tmp/btree_read_only/copy-wal");
+        btree.compact(Path::new("/tmp/btree_read_only/copy"))?;
+        drop(btree);
+
+        assert_eq!(fs::read("/tmp/btree_read_only/db")?, tree_file);
+        assert_eq!(fs::read("/tmp/btree_read_only/db-wal")?, wal);
+        Ok(())
+    }
+
+    #[test]
+    fn readers_run_alongside_writer() -> Result<(), Error> {
+        use crate::btree::{BTree, BTreeBuilder};
+        use crate::error::Error;
+        use crate::node_type::KeyValuePair;
+        use crate::snapshot::Snapshot;
+        use std::fs;
+        use std::thread;
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<BTree>();
+        assert_send_sync::<Snapshot>();
+
+        fs::create_dir_all("/tmp/btree_concurrent")?;
+        let btree = BTreeBuilder::new()
+            .path("/tmp/btree_concurrent/db")
+            .b_parameter(2)
+            .build()?;
+        for i in 0..100 {
+            btree.insert(KeyValuePair::new(format!("{:03}", i), i.to_string()))?;
+        }
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let btree = btree.clone();
+                thread::spawn(move || -> Result<(), Error> {
+                    for _ in 0..10 {
+                        for i in 0..100 {
+                            let kv = btree.search(format!("{:03}", i))?;
+                            assert_eq!(kv.value, i.to_string());
+                        }
+                        // An iterator reads a single committed root, thus a prefix of the sequential writes.
+                        let keys = btree
+                            .range("000".."999")?
+                            .map(|pair| pair.map(|pair| pair.key))
+                            .collect::<Result<Vec<_>, Error>>()?;
+                        let expected: Vec<String> =
+                            (0..keys.len()).map(|i| format!("{:03}", i)).collect();
+                        assert!(keys.len() >= 100);
+                        assert_eq!(keys, expected);
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        // Writes from another thread are serialized with the writes of this one.
+        let writer = {
+            let btree = btree.clone();
+            thread::spawn(move || -> Result<(), Error> {
+                for i in 0..100 {
+                    btree.upsert(KeyValuePair::new(format!("x{:03}", i), i.to_string()))?;
+                }
+                Ok(())
+            })
+        };
+        for i in 100..300 {
+            btree.insert(KeyValuePair::new(format!("{:03}", i), i.to_string()))?;
+        }
+        for handle in readers.into_iter().chain(std::iter::once(writer)) {
+            handle.join().map_err(|_| Error::UnexpectedError)??;
+        }
+        assert_eq!(btree.iter()?.count(), 400);
+
+        // Reads are not blocked by a transaction in progress, nor do they observe its writes.
+        let mut transaction = btree.begin()?;
+        transaction.insert(KeyValuePair::new("zzz".to_string(), "z".to_string()))?;
+        let reader = btree.clone();
+        let res = thread::spawn(move || reader.search("zzz".to_string()))
+            .join()
+            .map_err(|_| Error::UnexpectedError)?;
+        assert!(matches!(res, Err(Error::KeyNotFound)));
+        transaction.commit()?;
+        assert_eq!(btree.search("zzz".to_string())?.value, "z");
+        Ok(())
+    }
+
+    #[test]
+    fn readers_progress_while_writer_commits() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::error::Error;
+        use crate::node_type::{KeyValuePair, Offset};
+        use crate::page::Page;
+        use crate::storage::{Access, Backend, MemoryBackend, PageStore, Storage};
+        use std::path::Path;
+        use std::sync::mpsc::{self, Receiver, Sender};
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+        use std::time::Duration;
+
+        type Stall = Arc<Mutex<Option<(Sender<()>, Receiver<()>)>>>;
+
+        /// StallingBackend holds the next sync of a tree file, signalling once it is entered
+        /// and waiting to be released, e.g. to keep a commit in progress.
+        struct StallingBackend {
+            inner: MemoryBackend,
+            stall: Stall,
+        }
+        struct StallingPages {
+            inner: Box<dyn PageStore>,
+            stall: Stall,
+        }
+        impl Backend for StallingBackend {
+            fn open_pages(&self, path: &Path, access: Access) -> Result<Box<dyn PageStore>, Error> {
+                Ok(Box::new(StallingPages {
+                    inner: self.inner.open_pages(path, access)?,
+                    stall: Arc::clone(&self.stall),
+                }))
+            }
+            fn open_log(&self, path: &Path, access: Access) -> Result<Box<dyn Storage>, Error> {
+                self.inner.open_log(path, access)
+            }
+            fn exists(&self, path: &Path) -> bool {
+                self.inner.exists(path)
+            }
+            fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+                self.inner.rename(from, to)
+            }
+        }
+        impl PageStore for StallingPages {
+            fn read_page(&self, offset: &Offset) -> Result<Page, Error> {
+                self.inner.read_page(offset)
+            }
+            fn write_page(&mut self, offset: &Offset, page: &Page) -> Result<(), Error> {
+                self.inner.write_page(offset, page)
+            }
+            fn allocate_page(&mut self) -> Result<Offset, Error> {
+                self.inner.allocate_page()
+            }
+            fn num_pages(&self) -> usize {
+                self.inner.num_pages()
+            }
+            fn truncate(&mut self, num_pages: usize) -> Result<(), Error> {
+                self.inner.truncate(num_pages)
+            }
+            fn sync(&self) -> Result<(), Error> {
+                let stall = self.stall.lock()?.take();
+                if let Some((entered, release)) = stall {
+                    entered.send(()).map_err(|_| Error::UnexpectedError)?;
+                    release.recv().map_err(|_| Error::UnexpectedError)?;
+                }
+                self.inner.sync()
+            }
+            fn try_lock(&self, shared: bool) -> Result<bool, Error> {
+                self.inner.try_lock(shared)
+            }
+        }
+
+        let stall: Stall = Arc::default();
+        let btree = BTreeBuilder::new()
+            .path("db")
+            .b_parameter(2)
+            .backend(StallingBackend {
+                inner: MemoryBackend::new(),
+                stall: Arc::clone(&stall),
+            })
+            .build()?;
+        for i in 0..100 {
+            btree.insert(KeyValuePair::new(format!("{:03}", i), i.to_string()))?;
+        }
+
+        // The writer is held while syncing the pages of its commit.
+        let (entered_tx, entered_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        *stall.lock()? = Some((entered_tx, release_rx));
+        let writer = {
+            let btree = btree.clone();
+            thread::spawn(move || {
+                btree.insert(KeyValuePair::new("new".to_string(), "v".to_string()))
+            })
+        };
+        entered_rx.recv().map_err(|_| Error::UnexpectedError)?;
+
+        // Readers go through the pager and its cache alongside the commit, not observing it yet.
+        let (done_tx, done_rx) = mpsc::channel();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let btree = btree.clone();
+                let done = done_tx.clone();
+                thread::spawn(move || -> Result<(), Error> {
+                    for i in 0..100 {
+                        assert_eq!(btree.search(format!("{:03}", i))?.value, i.to_string());
+                    }
+                    assert!(matches!(
+                        btree.search("new".to_string()),
+                        Err(Error::KeyNotFound)
+                    ));
+                    done.send(()).map_err(|_| Error::UnexpectedError)
+                })
+            })
+            .collect();
+        let progressed =
+            (0..readers.len()).all(|_| done_rx.recv_timeout(Duration::from_secs(10)).is_ok());
+        release_tx.send(()).map_err(|_| Error::UnexpectedError)?;
+        assert!(progressed);
+        for handle in readers {
+            handle.join().map_err(|_| Error::UnexpectedError)??;
+        }
+        writer.join().map_err(|_| Error::UnexpectedError)??;
+        assert_eq!(btree.search("new".to_string())?.value, "v");
+        Ok(())
+    }
+
+    #[test]
+    fn tree_file_is_locked_while_in_use() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::error::Error;
+        use crate::node_type::KeyValuePair;
+        use std::fs;
+
+        fs::create_dir_all("/tmp/btree_lock")?;
+        let builder = BTreeBuilder::new()
+            .path("/tmp/btree_lock/db")
+            .b_parameter(2);
+        let btree = builder.build()?;
+        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+
+        // A writer excludes both writers and readers, without the file being truncated.
+        assert!(matches!(builder.build(), Err(Error::Locked(_))));
+        assert!(matches!(builder.open(), Err(Error::Locked(_))));
+        let read_only = BTreeBuilder::new()
+            .path("/tmp/btree_lock/db")
+            .b_parameter(2)
+            .read_only(true);
+        assert!(matches!(read_only.open(), Err(Error::Locked(_))));
+        assert_eq!(btree.search("a".to_string())?.value, "shalom");
+        drop(btree);
+
+        // Readers share the file, excluding writers only.
+        let first = read_only.open()?;
+        let second = read_only.open()?;
+        assert!(matches!(builder.open(), Err(Error::Locked(_))));
+        assert_eq!(first.search("a".to_string())?.value, "shalom");
+        assert_eq!(second.search("a".to_string())?.value, "shalom");
+        drop(first);
+        drop(second);
+
+        let btree = builder.open()?;
+        assert_eq!(btree.search("a".to_string())?.value, "shalom");
+        Ok(())
+    }
+
+    #[test]
+    fn page_cache_serves_repeated_reads() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::fs;
+
+        fs::create_dir_all("/tmp/btree_cache")?;
+        let btree = BTreeBuilder::new()
+            .path("/tmp/btree_cache/db")
+            .b_parameter(2)
+            .cache_size(64)
+            .build()?;
+        for i in 0..50 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), i.to_string()))?;
+        }
+        drop(btree);
+
+        let btree = BTreeBuilder::new()
+            .path("/tmp/btree_cache/db")
+            .b_parameter(2)
+            .cache_size(64)
+            .open()?;
+        let before = btree.cache_stats()?;
+        btree.search("07".to_string())?;
+        let first = btree.cache_stats()?;
+        assert!(first.misses > before.misses);
+        // The pages on the path to the key are read from disk once.
+        for _ in 0..10 {
+            assert_eq!(btree.search("07".to_string())?.value, "7");
+        }
+        let repeated = btree.cache_stats()?;
+        assert_eq!(repeated.misses, first.misses);
+        assert!(repeated.hits >= first.hits + 10);
+
+        // Written pages replace their cached copies.
+        btree.upsert(KeyValuePair::new("07".to_string(), "seven".to_string()))?;
+        assert_eq!(btree.search("07".to_string())?.value, "seven");
+        assert_eq!(btree.iter()?.count(), 50);
+        drop(btree);
+
+        // Without a cache every read goes to disk.
+        let btree = BTreeBuilder::new()
+            .path("/tmp/btree_cache/db")
+            .b_parameter(2)
+            .cache_size(0)
+            .open()?;
+        for _ in 0..10 {
+            assert_eq!(btree.search("07".to_string())?.value, "seven");
+        }
+        assert_eq!(btree.cache_stats()?.hits, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn mmap_reads_growing_file() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::mmap::MmapBackend;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::page_layout::PAGE_SIZE;
+        use std::fs;
+
+        fs::create_dir_all("/tmp/btree_mmap")?;
+        // Every read goes to the map rather than to the page cache.
+        let builder = BTreeBuilder::new()
+            .path("/tmp/btree_mmap/db")
+            .b_parameter(2)
+            .cache_size(0)
+            .backend(MmapBackend);
+        let btree = builder.build()?;
+        // The map grows along with the file as pages are allocated.
+        for i in 0..200 {
+            let value = match i % 20 {
+                0 => "v".repeat(PAGE_SIZE * 2),
+                _ => i.to_string(),
+            };
+            btree.insert(KeyValuePair::new(format!("{:03}", i), value))?;
+            assert_eq!(btree.search(format!("{:03}", i))?.key, format!("{:03}", i));
+        }
+        for i in (0..200).filter(|i| i % 3 == 0) {
+            btree.delete(Key(format!("{:03}", i)))?;
+        }
+        assert_eq!(
+            btree.search("020".to_string())?.value,
+            "v".repeat(PAGE_SIZE * 2)
+        );
+        let expected = btree.iter()?.collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(expected.len(), 133);
+        drop(btree);
+
+        let btree = builder.read_only(true).open()?;
+        let pairs = btree.iter()?.collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(pairs, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn memory_backend_keeps_tree_in_memory() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::page_layout::PAGE_SIZE;
+        use crate::storage::{Backend, MemoryBackend};
+        use std::path::Path;
+
+        let backend = MemoryBackend::new();
+        let builder = BTreeBuilder::new()
+            .path("/tmp/btree_memory/db")
+            .b_parameter(2)
+            .auto_checkpoint(Some(1024))
+            .backend(backend.clone());
+        let btree = builder.build()?;
+        for i in 0..200 {
+            let value = match i % 20 {
+                0 => "v".repeat(PAGE_SIZE * 2),
+                _ => i.to_string(),
+            };
+            btree.insert(KeyValuePair::new(format!("{:03}", i), value))?;
+        }
+        for i in (0..200).filter(|i| i % 3 == 0) {
+            btree.delete(Key(format!("{:03}", i)))?;
+        }
+        btree.vacuum()?;
+        btree.compact(Path::new("/tmp/btree_memory/compacted"))?;
+        let expected = btree.iter()?.collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(expected.len(), 133);
+        drop(btree);
+        // Nothing was written to disk.
+        assert!(!Path::new("/tmp/btree_memory").exists());
+        assert!(backend.exists(Path::new("/tmp/btree_memory/db-wal")));
+
+        // The tree is reopened through a clone of the backend it was built with.
+        let btree = builder.open()?;
+        let pairs = btree.iter()?.collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(pairs, expected);
+        let compacted = builder.path("/tmp/btree_memory/compacted").open()?;
+        let pairs = compacted.iter()?.collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(pairs, expected);
+
+        // Another backend does not see the tree.
+        assert!(matches!(
+            BTreeBuilder::new()
+                .path("/tmp/btree_memory/db")
+                .b_parameter(2)
+                .backend(MemoryBackend::new())
+                .create_if_missing(false)
+                .open(),
+            Err(Error::TreeNotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn memory_backend_locks_trees_in_use() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::error::Error;
+        use crate::node_type::KeyValuePair;
+        use crate::storage::MemoryBackend;
+
+        let backend = MemoryBackend::new();
+        let builder = BTreeBuilder::new()
+            .path("/tmp/btree_memory_lock/db")
+            .b_parameter(2)
+            .backend(backend.clone());
+        let btree = builder.build()?;
+        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+
+        // Trees opened through clones of a backend exclude each other just like files.
+        assert!(matches!(builder.open(), Err(Error::Locked(_))));
+        let read_only = BTreeBuilder::new()
+            .path("/tmp/btree_memory_lock/db")
+            .b_parameter(2)
+            .backend(backend)
+            .read_only(true);
+        assert!(matches!(read_only.open(), Err(Error::Locked(_))));
+        drop(btree);
+
+        let first = read_only.open()?;
+        let second = read_only.open()?;
+        assert!(matches!(builder.open(), Err(Error::Locked(_))));
+        assert_eq!(second.search("a".to_string())?.value, "shalom");
+        drop(first);
+        drop(second);
+
+        // Locks are released once the trees are dropped.
+        let btree = builder.open()?;
+        assert_eq!(btree.search("a".to_string())?.value, "shalom");
+        Ok(())
+    }
+
+    #[test]
+    fn open_validates_file_header() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_header")?;
+        BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_header/db"))
+            .b_parameter(2)
+            .build()?;
+
+        // A mismatching b parameter is refused.
+        let res = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_header/db"))
+            .b_parameter(3)
+            .open();
+        assert!(matches!(res, Err(Error::InvalidHeader(_))));
+
+        // So is a file which is not a btree file.
+        fs::write("/tmp/btree_header/garbage", vec![0xAB; 3 * 4096])?;
+        let res = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_header/garbage"))
+            .b_parameter(2)
+            .open();
+        assert!(matches!(res, Err(Error::InvalidHeader("not a btree file"))));
+        Ok(())
+    }
+
+    #[test]
+    fn insert_variable_length_pairs() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::page_layout::MAX_KEY_SIZE;
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_varlen")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_varlen/db"))
+            .b_parameter(100)
+            .build()?;
+        // Pages fill up long before reaching 2*b pairs - nodes are split by their size.
+        for i in 0..300 {
+            let key = format!("tenant/{:04}/user/{}", (i * 7) % 300, "x".repeat(i % 50));
+            btree.insert(KeyValuePair::new(key, "v".repeat(i + 200)))?;
+        }
+        for i in 0..300 {
+            let key = format!("tenant/{:04}/user/{}", (i * 7) % 300, "x".repeat(i % 50));
+            let kv = btree.search(key)?;
+            assert_eq!(kv.value, "v".repeat(i + 200));
+        }
+        for i in (0..300).step_by(2) {
+            let key = format!("tenant/{:04}/user/{}", (i * 7) % 300, "x".repeat(i % 50));
+            btree.delete(Key(key.clone()))?;
+            assert!(matches!(btree.search(key), Err(Error::KeyNotFound)));
+        }
+        for i in (1..300).step_by(2) {
+            let key = format!("tenant/{:04}/user/{}", (i * 7) % 300, "x".repeat(i % 50));
+            let kv = btree.search(key)?;
+            assert_eq!(kv.value, "v".repeat(i + 200));
+        }
+
+        let res = btree.insert(KeyValuePair::new(
+            "k".repeat(MAX_KEY_SIZE + 1),
+            "v".to_string(),
+        ));
+        assert!(matches!(res, Err(Error::KeyOverflowError)));
+        Ok(())
+    }
+
+    #[test]
+    fn large_values_use_overflow_pages() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::page_layout::PAGE_SIZE;
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_overflow")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_overflow/db"))
+            .b_parameter(2)
+            .build()?;
+        // A multi-byte character straddling the prefix boundary must not be split.
+        let document = format!("{{\"doc\": \"{}\"}}", "שלום ".repeat(1000));
+        btree.insert(KeyValuePair::new("doc".to_string(), document.clone()))?;
+        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+        btree.insert(KeyValuePair::new(
+            "z".to_string(),
+            "x".repeat(3 * PAGE_SIZE),
+        ))?;
+
+        assert_eq!(btree.search("doc".to_string())?.value, document);
+        assert_eq!(btree.search("a".to_string())?.value, "shalom");
+        assert_eq!(
+            btree.search("z".to_string())?.value,
+            "x".repeat(3 * PAGE_SIZE)
+        );
+
+        // Deleting the key releases its overflow pages which are then reused.
+        btree.delete(Key("z".to_string()))?;
+        let file_len = fs::metadata("/tmp/btree_overflow/db")?.len();
+        btree.insert(KeyValuePair::new(
+            "y".to_string(),
+            "y".repeat(3 * PAGE_SIZE),
+        ))?;
+        assert_eq!(
+            btree.search("y".to_string())?.value,
+            "y".repeat(3 * PAGE_SIZE)
+        );
+        // Only the copied root-to-leaf path and a freelist trunk page are appended to the file.
+        assert!(fs::metadata("/tmp/btree_overflow/db")?.len() <= file_len + 3 * PAGE_SIZE as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn scan_prefix_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_prefix")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_prefix/db"))
+            .b_parameter(2)
+            .build()?;
+        for tenant in &["acme", "acme-corp", "globex"] {
+            for user in 0..20 {
+                let key = format!("{}/user/{:02}", tenant, user);
+                btree.insert(KeyValuePair::new(key, user.to_string()))?;
+            }
+        }
+
+        let keys = btree
+            .scan_prefix("acme/")?
+            .map(|pair| pair.map(|pair| pair.key))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let expected: Vec<String> = (0..20)
+            .map(|user| format!("acme/user/{:02}", user))
+            .collect();
+        assert_eq!(keys, expected);
+
+        let pairs = btree
+            .scan_prefix("globex/user/1")?
+            .rev()
+            .collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(pairs.len(), 10);
+        assert_eq!(
+            pairs[0],
+            KeyValuePair::new("globex/user/19".to_string(), "19".to_string())
+        );
+
+        assert_eq!(btree.scan_prefix("")?.count(), 60);
+        assert_eq!(btree.scan_prefix("initech")?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_successor_works() {
+        use crate::btree::prefix_successor;
+
+        assert_eq!(prefix_successor("acme/"), Some("acme0".to_string()));
+        assert_eq!(prefix_successor("a\u{d7ff}"), Some("a\u{e000}".to_string()));
+        assert_eq!(prefix_successor("a\u{10ffff}"), Some("b".to_string()));
+        assert_eq!(prefix_successor("\u{10ffff}"), None);
+        assert_eq!(prefix_successor(""), None);
+    }
+
+    #[test]
+    fn upsert_and_update_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::error::Error;
+        use crate::node_type::KeyValuePair;
+        use crate::page_layout::PAGE_SIZE;
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_upsert")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_upsert/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v1".to_string()))?;
+        }
+
+        let res = btree.insert(KeyValuePair::new("07".to_string(), "v2".to_string()));
+        assert!(matches!(res, Err(Error::KeyAlreadyExists)));
+        let res = btree.update(KeyValuePair::new("30".to_string(), "v2".to_string()));
+        assert!(matches!(res, Err(Error::KeyNotFound)));
+
+        // Replace every value, moving some of them to overflow pages and back.
+        for i in 0..30 {
+            let value = "v2".repeat(i * PAGE_SIZE / 20);
+            let previous = btree.upsert(KeyValuePair::new(format!("{:02}", i), value))?;
+            assert_eq!(previous, Some("v1".to_string()));
+        }
+        for i in 0..30 {
+            let previous =
+                btree.update(KeyValuePair::new(format!("{:02}", i), "v3".to_string()))?;
+            assert_eq!(previous, "v2".repeat(i * PAGE_SIZE / 20));
+        }
+        assert_eq!(
+            btree.upsert(KeyValuePair::new("30".to_string(), "v3".to_string()))?,
+            None
+        );
+
+        // Keys stay unique.
+        let pairs = btree.iter()?.collect::<Result<Vec<_>, Error>>()?;
+        let expected: Vec<KeyValuePair> = (0..31)
+            .map(|i| KeyValuePair::new(format!("{:02}", i), "v3".to_string()))
+            .collect();
+        assert_eq!(pairs, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn delete_rebalances_siblings() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::error::Error;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_rebalance")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_rebalance/db"))
+            .b_parameter(2)
+            .build()?;
+        // Insert and delete keys in a scrambled order, some of the deletions leave a node
+        // next to a sibling it cannot be merged with.
+        let mut keys: Vec<String> = (0..200).map(|i| format!("{:03}", i)).collect();
+        let mut seed: u64 = 7;
+        for i in (1..keys.len()).rev() {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            keys.swap(i, (seed >> 33) as usize % (i + 1));
+        }
+        for key in &keys {
+            btree.insert(KeyValuePair::new(key.clone(), "v".to_string()))?;
+        }
+        for (i, key) in keys.iter().enumerate() {
+            btree.delete(Key(key.clone()))?;
+            assert!(matches!(btree.search(key.clone()), Err(Error::KeyNotFound)));
+            assert_eq!(btree.iter()?.count(), keys.len() - i - 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn delete_splits_merged_leaves_that_overflow_a_page() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::error::Error;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::collections::BTreeMap;
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_merge_split")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_merge_split/db"))
+            .b_parameter(50)
+            .build()?;
+        // Values around the size of a full leaf cell leave two merged leaves holding almost two pages,
+        // which have to be split again so that both halves fit a page.
+        let mut expected = BTreeMap::new();
+        let mut seed: u64 = 11;
+        for _ in 0..3000 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let key = format!("{:03}", (seed >> 33) % 300);
+            if (seed >> 20).is_multiple_of(3) {
+                if expected.remove(&key).is_some() {
+                    btree.delete(Key(key))?;
+                }
+            } else {
+                let value = "v".repeat((seed >> 40) as usize % 1500);
+                btree.upsert(KeyValuePair::new(key.clone(), value.clone()))?;
+                expected.insert(key, value);
+            }
+        }
+        let pairs = btree.iter()?.collect::<Result<Vec<_>, Error>>()?;
+        let expected: Vec<KeyValuePair> = expected
+            .into_iter()
+            .map(|(key, value)| KeyValuePair::new(key, value))
+            .collect();
+        assert_eq!(pairs, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn internal_nodes_with_long_keys_split_into_valid_halves() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::error::Error;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::collections::BTreeSet;
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_long_keys")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_long_keys/db"))
+            .b_parameter(2)
+            .build()?;
+        // Mixing short and long keys fills internal nodes by count and by size alike,
+        // either way both halves of a split keep a key of their own.
+        let mut expected = BTreeSet::new();
+        let mut seed: u64 = 5;
+        for _ in 0..2000 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let id = (seed >> 33) % 200;
+            let key = match id % 2 {
+                0 => format!("{:04}", id),
+                _ => format!("{:04}{}", id, "k".repeat(600)),
+            };
+            if (seed >> 20).is_multiple_of(3) {
+                if expected.remove(&key) {
+                    btree.delete(Key(key))?;
+                }
+            } else if expected.insert(key.clone()) {
+                btree.insert(KeyValuePair::new(key, "v".to_string()))?;
+            }
+        }
+        let keys = btree
+            .iter()?
+            .map(|pair| pair.map(|pair| pair.key))
+            .collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(keys, expected.into_iter().collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn at_version_reads_history() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::error::Error;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::page_layout::PAGE_SIZE;
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_versions")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_versions/db"))
+            .b_parameter(2)
+            .build()?;
+        // The empty tree is the first version.
+        assert_eq!(btree.versions()?, 1..=1);
+        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+        btree.insert(KeyValuePair::new("b".to_string(), "x".repeat(PAGE_SIZE)))?;
+        btree.delete(Key("a".to_string()))?;
+        assert_eq!(btree.versions()?, 1..=4);
+
+        assert_eq!(btree.at_version(1)?.iter().count(), 0);
+        let version = btree.at_version(2)?;
+        assert_eq!(version.version(), 2);
+        assert_eq!(version.search("a".to_string())?.value, "shalom");
+        assert!(matches!(
+            version.search("b".to_string()),
+            Err(Error::KeyNotFound)
+        ));
+        drop(version);
+        assert!(matches!(btree.at_version(5), Err(Error::VersionNotFound)));
+
+        // The history survives reopening the tree.
+        drop(btree);
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_versions/db"))
+            .b_parameter(2)
+            .open()?;
+        assert_eq!(btree.versions()?, 1..=4);
+        assert_eq!(
+            btree.at_version(3)?.search("a".to_string())?.value,
+            "shalom"
+        );
+
+        // Replacing the value releases its overflow page, the versions referencing it
+        // are gone once the page can be reused.
+        btree.upsert(KeyValuePair::new("b".to_string(), "y".to_string()))?;
+        assert_eq!(btree.versions()?, 5..=5);
+        assert!(matches!(btree.at_version(4), Err(Error::VersionNotFound)));
+        drop(btree);
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_versions/db"))
+            .b_parameter(2)
+            .open()?;
+        assert_eq!(btree.versions()?, 5..=5);
+        Ok(())
+    }
+
+    #[test]
+    fn collect_garbage_reuses_unreachable_pages() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::page_layout::PAGE_SIZE;
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_gc")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_gc/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..50 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), "v0".to_string()))?;
+        }
+        for round in 1..5 {
+            for i in 0..50 {
+                btree.upsert(KeyValuePair::new(
+                    format!("{:02}", i),
+                    format!("v{}", round),
+                ))?;
+            }
+        }
+        let snapshot = btree.snapshot()?;
+        assert!(btree.collect_garbage()? > 0);
+        // Only the current version, which is also held by the snapshot, is left.
+        assert_eq!(btree.versions()?.count(), 1);
+
+        // A workload of overwrites no longer grows the file,
+        // once the commit following the collection is flushed the freed pages are reused.
+        btree.upsert(KeyValuePair::new("00".to_string(), "v5".to_string()))?;
+        let file_len = fs::metadata("/tmp/btree_gc/db")?.len();
+        for i in 1..50 {
+            btree.upsert(KeyValuePair::new(format!("{:02}", i), "v5".to_string()))?;
+        }
+        assert_eq!(fs::metadata("/tmp/btree_gc/db")?.len(), file_len);
+
+        // The pages of the snapshot were not freed.
+        let pairs = snapshot.iter().collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(pairs.len(), 50);
+        assert!(pairs.iter().all(|pair| pair.value == "v4"));
+        drop(snapshot);
+
+        btree.collect_garbage()?;
+        for i in 0..50 {
+            btree.upsert(KeyValuePair::new(
+                format!("{:02}", i),
+                "x".repeat(PAGE_SIZE),
+            ))?;
+        }
+        btree.collect_garbage()?;
+        btree.upsert(KeyValuePair::new("00".to_string(), "y".repeat(PAGE_SIZE)))?;
+        let file_len = fs::metadata("/tmp/btree_gc/db")?.len();
+        for i in 1..50 {
+            btree.upsert(KeyValuePair::new(
+                format!("{:02}", i),
+                "y".repeat(PAGE_SIZE),
+            ))?;
+        }
+        assert_eq!(fs::metadata("/tmp/btree_gc/db")?.len(), file_len);
+        assert_eq!(btree.search("42".to_string())?.value, "y".repeat(PAGE_SIZE));
+        Ok(())
+    }
+
+    #[test]
+    fn open_recovers_free_pages() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::page_layout::PAGE_SIZE;
+        use std::fs;
+        use std::mem;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_freelist")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_freelist/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..50 {
+            btree.insert(KeyValuePair::new(
+                format!("{:02}", i),
+                "x".repeat(PAGE_SIZE),
+            ))?;
+        }
+        for i in 0..40 {
+            btree.delete(Key(format!("{:02}", i)))?;
+        }
+        btree.collect_garbage()?;
+        // The free pages are persisted by the following commit.
+        btree.insert(KeyValuePair::new("zz".to_string(), "z".to_string()))?;
+        drop(btree);
+
+        // The pages freed by the previous process are reused.
+        let file_len = fs::metadata("/tmp/btree_freelist/db")?.len();
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_freelist/db"))
+            .b_parameter(2)
+            .open()?;
+        for i in 0..40 {
+            btree.insert(KeyValuePair::new(
+                format!("{:02}", i),
+                "y".repeat(PAGE_SIZE),
+            ))?;
+        }
+        assert_eq!(fs::metadata("/tmp/btree_freelist/db")?.len(), file_len);
+
+        // Pages appended by a transaction which was never committed, e.g. due to a crash, are overwritten
+        // once the tree is reopened rather than leaked.
+        let committed_len = fs::metadata("/tmp/btree_freelist/db")?.len();
+        let mut transaction = btree.begin()?;
+        for i in 50..100 {
+            transaction.insert(KeyValuePair::new(
+                format!("{:02}", i),
+                "z".repeat(4 * PAGE_SIZE),
+            ))?;
+        }
+        mem::forget(transaction);
+        drop(btree);
+        let file_len = fs::metadata("/tmp/btree_freelist/db")?.len();
+        assert!(file_len > committed_len);
+
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_freelist/db"))
+            .b_parameter(2)
+            .open()?;
+        let mut transaction = btree.begin()?;
+        for i in 50..100 {
+            transaction.insert(KeyValuePair::new(
+                format!("{:02}", i),
+                "w".repeat(4 * PAGE_SIZE),
+            ))?;
+        }
+        transaction.commit()?;
+        // Only the freelist trunk pages written by the commit might be appended.
+        assert!(fs::metadata("/tmp/btree_freelist/db")?.len() <= file_len + 2 * PAGE_SIZE as u64);
+
+        assert_eq!(btree.iter()?.count(), 101);
+        assert_eq!(btree.search("05".to_string())?.value, "y".repeat(PAGE_SIZE));
+        assert_eq!(btree.search("45".to_string())?.value, "x".repeat(PAGE_SIZE));
+        assert_eq!(
+            btree.search("75".to_string())?.value,
+            "w".repeat(4 * PAGE_SIZE)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn commits_rewrite_the_head_of_the_freelist_alone() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::error::Error;
+        use crate::node_type::{Key, KeyValuePair, Offset};
+        use crate::page::Page;
+        use crate::page_layout::PAGE_SIZE;
+        use crate::storage::{Access, Backend, MemoryBackend, PageStore, Storage};
+        use std::path::Path;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        /// CountingBackend counts the pages written to tree files.
+        #[derive(Clone)]
+        struct CountingBackend {
+            inner: MemoryBackend,
+            writes: Arc<AtomicUsize>,
+        }
+        struct CountingPages {
+            inner: Box<dyn PageStore>,
+            writes: Arc<AtomicUsize>,
+        }
+        impl Backend for CountingBackend {
+            fn open_pages(&self, path: &Path, access: Access) -> Result<Box<dyn PageStore>, Error> {
+                Ok(Box::new(CountingPages {
+                    inner: self.inner.open_pages(path, access)?,
+                    writes: Arc::clone(&self.writes),
+                }))
+            }
+            fn open_log(&self, path: &Path, access: Access) -> Result<Box<dyn Storage>, Error> {
+                self.inner.open_log(path, access)
+            }
+            fn exists(&self, path: &Path) -> bool {
+                self.inner.exists(path)
+            }
+            fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+                self.inner.rename(from, to)
+            }
+        }
+        impl PageStore for CountingPages {
+            fn read_page(&self, offset: &Offset) -> Result<Page, Error> {
+                self.inner.read_page(offset)
+            }
+            fn write_page(&mut self, offset: &Offset, page: &Page) -> Result<(), Error> {
+                self.writes.fetch_add(1, Ordering::SeqCst);
+                self.inner.write_page(offset, page)
+            }
+            fn allocate_page(&mut self) -> Result<Offset, Error> {
+                self.inner.allocate_page()
+            }
+            fn num_pages(&self) -> usize {
+                self.inner.num_pages()
+            }
+            fn truncate(&mut self, num_pages: usize) -> Result<(), Error> {
+                self.inner.truncate(num_pages)
+            }
+            fn sync(&self) -> Result<(), Error> {
+                self.inner.sync()
+            }
+            fn try_lock(&self, shared: bool) -> Result<bool, Error> {
+                self.inner.try_lock(shared)
+            }
+        }
+
+        let backend = CountingBackend {
+            inner: MemoryBackend::new(),
+            writes: Arc::new(AtomicUsize::new(0)),
+        };
+        let btree = BTreeBuilder::new()
+            .path("db")
+            .b_parameter(2)
+            .backend(backend.clone())
+            .build()?;
+        for i in 0..300 {
+            btree.insert(KeyValuePair::new(
+                format!("{:04}", i),
+                "x".repeat(3 * PAGE_SIZE),
+            ))?;
+        }
+        for i in 1..300 {
+            btree.delete(Key(format!("{:04}", i)))?;
+        }
+        // Over a thousand pages are freed, listed by a chain of several trunk pages.
+        assert!(btree.collect_garbage()? > 1000);
+        btree.insert(KeyValuePair::new("a".to_string(), "v".to_string()))?;
+
+        // A commit writes the copied root-to-leaf path along with the trunk pages at the head of the chain.
+        for i in 0..20 {
+            let writes = backend.writes.load(Ordering::SeqCst);
+            btree.upsert(KeyValuePair::new("b".to_string(), i.to_string()))?;
+            assert!(backend.writes.load(Ordering::SeqCst) - writes <= 6);
+        }
+        drop(btree);
+
+        // The kept trunk pages still list the free pages once the tree is reopened.
+        let btree = BTreeBuilder::new()
+            .path("db")
+            .b_parameter(2)
+            .backend(backend.clone())
+            .open()?;
+        assert_eq!(
+            btree.search("0000".to_string())?.value,
+            "x".repeat(3 * PAGE_SIZE)
+        );
+        assert_eq!(btree.search("b".to_string())?.value, "19");
+        for i in 1..300 {
+            btree.insert(KeyValuePair::new(format!("{:04}", i), "y".to_string()))?;
+        }
+        assert_eq!(btree.search("0150".to_string())?.value, "y");
+        assert_eq!(btree.iter()?.count(), 302);
+        Ok(())
+    }
+
+    #[test]
+    fn damaged_pages_are_detected() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{KeyValuePair, Offset};
+        use crate::page::Page;
+        use crate::page_layout::PAGE_SIZE;
+        use std::fs::{self, OpenOptions};
+        use std::io::{Seek, SeekFrom, Write};
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_corruption")?;
+        // The pages are damaged behind the tree's back, thus they must not be served from the cache.
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_corruption/db"))
+            .b_parameter(2)
+            .cache_size(0)
+            .build()?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), i.to_string()))?;
+        }
+        drop(btree);
+
+        let flip_byte = |offset: usize| -> Result<(), Error> {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open("/tmp/btree_corruption/db")?;
+            file.seek(SeekFrom::Start(offset as u64))?;
+            file.write_all(&[0xFF])?;
+            Ok(())
+        };
+        let open = || {
+            BTreeBuilder::new()
+                .path(Path::new("/tmp/btree_corruption/db"))
+                .b_parameter(2)
+                .cache_size(0)
+                .open()
+        };
+        // A damaged superblock, e.g. torn by a checkpoint, is rebuilt out of the wal and rewritten.
+        flip_byte(20)?;
+        let btree = open()?;
+        assert_eq!(btree.search("13".to_string())?.value, "13");
+        drop(btree);
+        let mut data = [0x00; PAGE_SIZE];
+        data.copy_from_slice(&fs::read("/tmp/btree_corruption/db")?[..PAGE_SIZE]);
+        assert!(Page::new(data).verify_checksum());
+        let btree = open()?;
+        assert_eq!(btree.iter()?.count(), 20);
+
+        let root = btree.writer()?.wal.get_root()?;
+        flip_byte(root.0 + 100)?;
+        assert!(matches!(
+            btree.search("05".to_string()),
+            Err(Error::Corruption { offset }) if offset == root
+        ));
+        drop(btree);
+
+        // Without the wal there is nothing to rebuild the superblock from.
+        flip_byte(20)?;
+        fs::remove_file("/tmp/btree_corruption/db-wal")?;
+        assert!(matches!(
+            open(),
+            Err(Error::Corruption { offset: Offset(0) })
+        ));
+        Ok(())
+    }
 }
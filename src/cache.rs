@@ -0,0 +1,154 @@
+use crate::error::Error;
+use crate::page::Page;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+/// The number of pages cached by default, four megabytes worth of pages.
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+
+/// CacheStats counts the page reads served by the page cache (hits) and the ones which went to disk (misses).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// PageCache is a least recently used cache of the pages of a pager file, keyed by their offset.
+/// Written pages are put in the cache as well, so it always holds the content found on disk.
+/// Pages are looked up by readers sharing the cache, a hit only records the tick the page was used at
+/// while inserts take the cache for themselves.
+pub(crate) struct PageCache {
+    /// The maximum number of pages held, zero disables the cache.
+    capacity: usize,
+    /// The cached pages along with the tick they were last used at.
+    pages: RwLock<HashMap<usize, (Page, AtomicU64)>>,
+    tick: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl PageCache {
+    pub fn new(capacity: usize) -> PageCache {
+        PageCache {
+            capacity,
+            pages: RwLock::new(HashMap::new()),
+            tick: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// get returns a copy of the page at the given offset if it is cached, counting a hit or a miss.
+    pub fn get(&self, offset: usize) -> Result<Option<Page>, Error> {
+        match self.pages.read()?.get(&offset) {
+            Some((page, used)) => {
+                used.store(self.next_tick(), Ordering::Relaxed);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Ok(Some(page.clone()))
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Ok(None)
+            }
+        }
+    }
+
+    /// insert caches the page at the given offset, replacing a previous copy and evicting
+    /// the least recently used pages if the cache is full.
+    pub fn insert(&self, offset: usize, page: Page) -> Result<(), Error> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let tick = AtomicU64::new(self.next_tick());
+        let mut pages = self.pages.write()?;
+        pages.insert(offset, (page, tick));
+        evict(&mut pages, self.capacity);
+        Ok(())
+    }
+
+    /// capacity returns the maximum number of pages held.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// set_capacity changes the maximum number of pages held, evicting pages if there are more.
+    pub fn set_capacity(&mut self, capacity: usize) -> Result<(), Error> {
+        self.capacity = capacity;
+        evict(self.pages.get_mut()?, capacity);
+        Ok(())
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn next_tick(&self) -> u64 {
+        self.tick.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// evict drops the least recently used pages once there are more than the capacity.
+/// An eighth of the capacity is evicted along with them, so the pages are ordered once every several inserts.
+fn evict(pages: &mut HashMap<usize, (Page, AtomicU64)>, capacity: usize) {
+    if pages.len() <= capacity {
+        return;
+    }
+    let count = pages.len() - capacity + capacity / 8;
+    let mut recency: Vec<(u64, usize)> = pages
+        .iter()
+        .map(|(offset, (_, used))| (used.load(Ordering::Relaxed), *offset))
+        .collect();
+    recency.select_nth_unstable(count - 1);
+    for (_, offset) in &recency[..count] {
+        pages.remove(offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn evicts_least_recently_used() -> Result<(), Error> {
+        use crate::cache::{CacheStats, PageCache};
+        use crate::page::Page;
+        use crate::page_layout::PAGE_SIZE;
+
+        let page = |byte: u8| Page::new([byte; PAGE_SIZE]);
+        let mut cache = PageCache::new(2);
+        cache.insert(1, page(1))?;
+        cache.insert(2, page(2))?;
+        assert!(cache.get(1)?.is_some());
+        // Page 2 is the least recently used.
+        cache.insert(3, page(3))?;
+        assert!(cache.get(2)?.is_none());
+        assert_eq!(cache.get(1)?.map(|page| page.get_data()[0]), Some(1));
+        assert_eq!(cache.get(3)?.map(|page| page.get_data()[0]), Some(3));
+
+        // Inserting a cached offset replaces its page.
+        cache.insert(3, page(4))?;
+        assert_eq!(cache.get(3)?.map(|page| page.get_data()[0]), Some(4));
+        assert_eq!(cache.stats(), CacheStats { hits: 4, misses: 1 });
+
+        // Full caches evict an eighth of their pages at once, the least recently used ones.
+        cache.set_capacity(16)?;
+        for offset in 0..16 {
+            cache.insert(offset, page(0))?;
+        }
+        cache.get(0)?;
+        cache.insert(16, page(0))?;
+        assert!(cache.get(0)?.is_some());
+        assert!(cache.get(16)?.is_some());
+        assert!((1..4).all(|offset| matches!(cache.get(offset), Ok(None))));
+        assert!((4..16).all(|offset| matches!(cache.get(offset), Ok(Some(_)))));
+
+        cache.set_capacity(0)?;
+        cache.insert(1, page(1))?;
+        assert!(cache.get(1)?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+/// The reversed Castagnoli polynomial, as used by iSCSI, ext4 and SQLite's WAL.
+const CRC32C_POLYNOMIAL: u32 = 0x82F6_3B78;
+
+/// The CRC of every possible byte, computed at compile time.
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ CRC32C_POLYNOMIAL,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+}
+
+/// crc32c returns the CRC32C checksum of the given bytes,
+/// using the dedicated instruction of the CPU when there is one.
+pub fn crc32c(data: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("sse4.2") {
+            // Safety: the instructions used were just detected.
+            return unsafe { crc32c_sse42(data) };
+        }
+    }
+    crc32c_software(data)
+}
+
+fn crc32c_software(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| {
+        CRC32C_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn crc32c_sse42(data: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+    use std::convert::TryInto;
+
+    let mut crc = !0u64;
+    let mut words = data.chunks_exact(8);
+    for word in &mut words {
+        crc = _mm_crc32_u64(crc, u64::from_le_bytes(word.try_into().unwrap_or_default()));
+    }
+    let mut crc = crc as u32;
+    for byte in words.remainder() {
+        crc = _mm_crc32_u8(crc, *byte);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn crc32c_works() {
+        use crate::checksum::crc32c;
+
+        // Check values from RFC 3720.
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(&[0x00; 32]), 0x8A91_36AA);
+        assert_eq!(crc32c(&[0xFF; 32]), 0x62A8_AB43);
+    }
+
+    #[test]
+    fn crc32c_implementations_agree() {
+        use crate::checksum::{crc32c, crc32c_software};
+
+        let data: Vec<u8> = (0..4099u32).map(|i| (i * 31 % 251) as u8).collect();
+        for len in [0, 1, 7, 8, 9, 4092, 4099] {
+            assert_eq!(crc32c(&data[..len]), crc32c_software(&data[..len]));
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use crate::btree::overflow_prefix_len;
+use crate::error::Error;
+use crate::node::Node;
+use crate::node_type::{Key, KeyValuePair, NodeType, Offset};
+use crate::overflow::OverflowPage;
+use crate::page::Page;
+use crate::page_layout::{
+    internal_cell_size, leaf_cell_size, INTERNAL_NODE_HEADER_SIZE, LEAF_NODE_HEADER_SIZE,
+    OVERFLOW_DATA_SIZE, PAGE_SIZE, PTR_SIZE, SUPERBLOCK_OFFSET, USABLE_PAGE_SIZE,
+};
+use crate::pager::Pager;
+use crate::snapshot::Snapshot;
+use crate::storage::Backend;
+use crate::superblock::Superblock;
+use crate::wal::Record;
+use std::cmp;
+use std::convert::TryFrom;
+use std::ops;
+use std::path::Path;
+use uuid::Uuid;
+
+/// Level is a level of the compacted tree, from the leaves up to the root.
+struct Level {
+    /// The entries (pairs or children) of each node of the level.
+    nodes: Vec<ops::Range<usize>>,
+    /// The offset of the first node of the level, the nodes of a level are written one after the other.
+    offset: usize,
+}
+
+/// write_compacted writes the pairs of the snapshot into a new tree file at dest, stored with the given backend, with the given id,
+/// returning its superblock which records the root to be logged. The leaves are densely packed and written in key order right
+/// after the superblock, followed by the internal nodes level by level and then by the overflow pages.
+pub(crate) fn write_compacted(
+    source: &Snapshot,
+    b: usize,
+    id: Uuid,
+    backend: &dyn Backend,
+    dest: &Path,
+) -> Result<Superblock, Error> {
+    // The first pass only lays out the tree, the pairs are streamed once more to write it.
+    let mut keys = vec![];
+    let mut cell_sizes = vec![];
+    let mut overflow_pages = 0;
+    for pair in source.iter() {
+        let pair = pair?;
+        match overflow_prefix_len(&pair) {
+            Some(prefix_len) => {
+                cell_sizes.push(leaf_cell_size(pair.key.len(), prefix_len) + PTR_SIZE);
+                overflow_pages += (pair.value.len() - prefix_len).div_ceil(OVERFLOW_DATA_SIZE);
+            }
+            None => cell_sizes.push(pair.cell_size()),
+        }
+        keys.push(pair.key);
+    }
+
+    let mut levels = vec![Level {
+        nodes: pack(
+            &cell_sizes,
+            USABLE_PAGE_SIZE - LEAF_NODE_HEADER_SIZE,
+            2 * b,
+            cmp::max(b, 2) - 1,
+        ),
+        offset: PAGE_SIZE,
+    }];
+    // The largest key under each node of the last level.
+    let mut max_keys: Vec<&String> = levels[0]
+        .nodes
+        .iter()
+        .filter_map(|node| node.end.checked_sub(1).map(|idx| &keys[idx]))
+        .collect();
+    while levels[levels.len() - 1].nodes.len() > 1 {
+        let below = &levels[levels.len() - 1];
+        let offset = below.offset + below.nodes.len() * PAGE_SIZE;
+        let child_sizes: Vec<usize> = max_keys
+            .iter()
+            .map(|key| PTR_SIZE + internal_cell_size(key.len()))
+            .collect();
+        let nodes = pack(
+            &child_sizes,
+            USABLE_PAGE_SIZE - INTERNAL_NODE_HEADER_SIZE,
+            2 * b,
+            b,
+        );
+        max_keys = nodes.iter().map(|node| max_keys[node.end - 1]).collect();
+        levels.push(Level { nodes, offset });
+    }
+    let root_level = &levels[levels.len() - 1];
+    let root_offset = Offset(root_level.offset);
+    let mut next_overflow = root_level.offset + PAGE_SIZE;
+
+    let mut pager = Pager::new(backend, dest)?;
+    // The pages are allocated in advance, the superblock followed by the nodes and then by the overflow pages.
+    for _ in 0..next_overflow / PAGE_SIZE + overflow_pages {
+        pager.allocate_page()?;
+    }
+
+    let mut pairs = source.iter();
+    let parents = parent_offsets(&levels, 0);
+    for (idx, node) in levels[0].nodes.iter().enumerate() {
+        let mut stored = vec![];
+        for _ in node.clone() {
+            let pair = pairs.next().ok_or(Error::UnexpectedError)??;
+            let prefix_len = match overflow_prefix_len(&pair) {
+                Some(prefix_len) => prefix_len,
+                None => {
+                    stored.push(pair);
+                    continue;
+                }
+            };
+            // The chain is written to consecutive pages, each pointing at the following one.
+            let chunks: Vec<&[u8]> = pair.value.as_bytes()[prefix_len..]
+                .chunks(OVERFLOW_DATA_SIZE)
+                .collect();
+            let overflow = Offset(next_overflow);
+            for (i, chunk) in chunks.iter().enumerate() {
+                let next = match i + 1 < chunks.len() {
+                    true => Some(Offset(next_overflow + PAGE_SIZE)),
+                    false => None,
+                };
+                let page = Page::try_from(&OverflowPage::new(next, chunk.to_vec()))?;
+                pager.write_page_at_offset(page, &Offset(next_overflow))?;
+                next_overflow += PAGE_SIZE;
+            }
+            let mut prefix = KeyValuePair::new(pair.key, pair.value[..prefix_len].to_string());
+            prefix.overflow = Some(overflow);
+            stored.push(prefix);
+        }
+        let leaf = Node::new(
+            NodeType::Leaf(stored),
+            levels.len() == 1,
+            parents[idx].clone(),
+        );
+        pager.write_page_at_offset(
+            Page::try_from(&leaf)?,
+            &Offset(levels[0].offset + idx * PAGE_SIZE),
+        )?;
+    }
+    if next_overflow != root_level.offset + (overflow_pages + 1) * PAGE_SIZE {
+        // The tree was modified in between the two passes.
+        return Err(Error::UnexpectedError);
+    }
+
+    let mut max_keys: Vec<&String> = levels[0]
+        .nodes
+        .iter()
+        .filter_map(|node| node.end.checked_sub(1).map(|idx| &keys[idx]))
+        .collect();
+    for (level_idx, level) in levels.iter().enumerate().skip(1) {
+        let below = &levels[level_idx - 1];
+        let parents = parent_offsets(&levels, level_idx);
+        for (idx, node) in level.nodes.iter().enumerate() {
+            let children = node
+                .clone()
+                .map(|child| Offset(below.offset + child * PAGE_SIZE))
+                .collect();
+            // The largest key under the last child is kept by the parent.
+            let node_keys = max_keys[node.start..node.end - 1]
+                .iter()
+                .map(|key| Key(key.to_string()))
+                .collect();
+            let internal = Node::new(
+                NodeType::Internal(children, node_keys),
+                level_idx == levels.len() - 1,
+                parents[idx].clone(),
+            );
+            pager.write_page_at_offset(
+                Page::try_from(&internal)?,
+                &Offset(level.offset + idx * PAGE_SIZE),
+            )?;
+        }
+        max_keys = level
+            .nodes
+            .iter()
+            .map(|node| max_keys[node.end - 1])
+            .collect();
+    }
+    // The compacted tree has no free pages.
+    let record = Record {
+        version: 1,
+        root: root_offset.clone(),
+        freelist: Offset(0),
+        num_pages: next_overflow / PAGE_SIZE,
+        oldest_version: 1,
+    };
+    let mut superblock = Superblock::new(b, root_offset);
+    superblock.id = id;
+    superblock.set_record(record);
+    pager.write_page_at_offset(Page::try_from(&superblock)?, &Offset(SUPERBLOCK_OFFSET))?;
+    pager.sync()?;
+    Ok(superblock)
+}
+
+/// parent_offsets returns the offset of the parent of each node of a level, None for the root.
+fn parent_offsets(levels: &[Level], level_idx: usize) -> Vec<Option<Offset>> {
+    match levels.get(level_idx + 1) {
+        Some(parent) => parent
+            .nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, node)| {
+                node.clone()
+                    .map(move |_| Some(Offset(parent.offset + idx * PAGE_SIZE)))
+            })
+            .collect(),
+        None => vec![None],
+    }
+}
+
+/// pack groups consecutive entries of the given sizes into as few nodes as possible,
+/// each holding at most max_entries entries within room bytes. Entries are moved from the
+/// node before last into the last node as long as it holds fewer than min_entries entries.
+fn pack(
+    sizes: &[usize],
+    room: usize,
+    max_entries: usize,
+    min_entries: usize,
+) -> Vec<ops::Range<usize>> {
+    let mut nodes = vec![];
+    let mut start = 0;
+    let mut used = 0;
+    for (idx, size) in sizes.iter().enumerate() {
+        if idx > start && (idx - start >= max_entries || used + size > room) {
+            nodes.push(start..idx);
+            start = idx;
+            used = 0;
+        }
+        used += size;
+    }
+    // An empty tree is still made of a single empty leaf.
+    nodes.push(start..sizes.len());
+
+    if nodes.len() > 1 {
+        let last = nodes.len() - 1;
+        while nodes[last].len() < min_entries && nodes[last - 1].len() > min_entries {
+            let moved = nodes[last - 1].end - 1;
+            if used + sizes[moved] > room {
+                break;
+            }
+            used += sizes[moved];
+            nodes[last - 1].end -= 1;
+            nodes[last].start -= 1;
+        }
+    }
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn pack_works() {
+        use crate::compact::pack;
+
+        assert_eq!(pack(&[], 10, 4, 1), vec![0..0]);
+        assert_eq!(pack(&[3, 3, 3, 3, 3], 10, 4, 1), vec![0..3, 3..5]);
+        assert_eq!(pack(&[1; 9], 10, 4, 1), vec![0..4, 4..8, 8..9]);
+        // The last node is topped up from the one before it.
+        assert_eq!(pack(&[1; 9], 10, 4, 2), vec![0..4, 4..7, 7..9]);
+    }
+
+    #[test]
+    fn compact_packs_leaves_in_order() -> Result<(), Error> {
+        use crate::btree::{read_node, BTreeBuilder};
+        use crate::node_type::{Key, KeyValuePair, NodeType, Offset};
+        use crate::page_layout::{PAGE_SIZE, SUPERBLOCK_OFFSET};
+        use crate::pager::Pager;
+        use crate::storage::FileBackend;
+        use crate::superblock::Superblock;
+        use std::convert::TryFrom;
+        use std::fs;
+        use std::path::Path;
+        use std::sync::RwLock;
+
+        fs::create_dir_all("/tmp/btree_compact_source")?;
+        fs::create_dir_all("/tmp/btree_compact_dest")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_compact_source/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..200 {
+            let value = match i % 10 {
+                0 => "v".repeat(PAGE_SIZE * 2),
+                _ => i.to_string(),
+            };
+            btree.insert(KeyValuePair::new(format!("{:03}", i), value))?;
+        }
+        for i in (0..200).filter(|i| i % 3 == 0) {
+            btree.delete(Key(format!("{:03}", i)))?;
+        }
+
+        let _ = fs::remove_file("/tmp/btree_compact_dest/db");
+        let _ = fs::remove_file("/tmp/btree_compact_dest/db-wal");
+        btree.compact(Path::new("/tmp/btree_compact_dest/db"))?;
+        assert!(
+            fs::metadata("/tmp/btree_compact_dest/db")?.len()
+                < fs::metadata("/tmp/btree_compact_source/db")?.len()
+        );
+
+        // The leaves directly follow the superblock in key order.
+        // The pager is closed before the tree is opened, the two cannot hold the file at once.
+        let pager = RwLock::new(Pager::open(
+            &FileBackend,
+            Path::new("/tmp/btree_compact_dest/db"),
+        )?);
+        let superblock = Superblock::try_from(pager.read()?.get_page(&Offset(SUPERBLOCK_OFFSET))?)?;
+        let mut level = vec![superblock.root];
+        let mut leaves = vec![];
+        while let Some(offset) = level.first().cloned() {
+            match read_node(&pager, &offset)?.node_type {
+                NodeType::Internal(..) => {
+                    let mut below = vec![];
+                    for offset in &level {
+                        if let NodeType::Internal(children, _) =
+                            read_node(&pager, offset)?.node_type
+                        {
+                            below.extend(children);
+                        }
+                    }
+                    level = below;
+                }
+                _ => {
+                    leaves = level;
+                    break;
+                }
+            }
+        }
+        let offsets: Vec<Offset> = (1..=leaves.len()).map(|i| Offset(i * PAGE_SIZE)).collect();
+        assert_eq!(leaves, offsets);
+        drop(pager);
+
+        let compacted = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_compact_dest/db"))
+            .b_parameter(2)
+            .open()?;
+        let expected = btree.iter()?.collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(expected.len(), 133);
+        let pairs = compacted.iter()?.collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(pairs, expected);
+
+        // The compacted tree is written as usual.
+        compacted.insert(KeyValuePair::new("000".to_string(), "zero".to_string()))?;
+        compacted.delete(Key("100".to_string()))?;
+        assert_eq!(compacted.iter()?.count(), 133);
+        Ok(())
+    }
+
+    #[test]
+    fn vacuum_rewrites_tree_in_place() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::page_layout::PAGE_SIZE;
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_vacuum")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_vacuum/db"))
+            .b_parameter(3)
+            .build()?;
+        for i in 0..300 {
+            btree.insert(KeyValuePair::new(format!("{:03}", i), "x".repeat(i)))?;
+        }
+        let snapshot = btree.snapshot()?;
+        for i in 0..250 {
+            btree.delete(Key(format!("{:03}", i)))?;
+        }
+        btree.upsert(KeyValuePair::new("299".to_string(), "y".repeat(PAGE_SIZE)))?;
+
+        let file_len = fs::metadata("/tmp/btree_vacuum/db")?.len();
+        // The compacted file is named after the tree file, leaving files of the same stem alone.
+        fs::write("/tmp/btree_vacuum/db.vacuum", "keep")?;
+        btree.vacuum()?;
+        assert!(fs::metadata("/tmp/btree_vacuum/db")?.len() < file_len);
+        assert_eq!(fs::read("/tmp/btree_vacuum/db.vacuum")?, b"keep");
+        assert!(!Path::new("/tmp/btree_vacuum/db-vacuum").exists());
+        assert_eq!(btree.versions()?, 1..=1);
+        assert_eq!(btree.iter()?.count(), 50);
+        assert_eq!(
+            btree.search("299".to_string())?.value,
+            "y".repeat(PAGE_SIZE)
+        );
+        // Snapshots taken before vacuuming still read the previous file.
+        assert_eq!(snapshot.iter().count(), 300);
+        drop(snapshot);
+
+        btree.insert(KeyValuePair::new("300".to_string(), "z".to_string()))?;
+        drop(btree);
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_vacuum/db"))
+            .b_parameter(3)
+            .open()?;
+        let keys = btree
+            .iter()?
+            .map(|pair| pair.map(|pair| pair.key))
+            .collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(
+            keys,
+            (250..=300).map(|i| format!("{:03}", i)).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+}
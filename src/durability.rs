@@ -0,0 +1,41 @@
+use std::time::{Duration, Instant};
+
+/// Durability determines when the pages written by a commit, and its wal record, are flushed to disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Never flush, leaving it to the operating system. Commits may be lost
+    /// (and recent pages damaged) if the machine crashes.
+    None,
+    /// Flush the data pages and then the wal record on every commit.
+    #[default]
+    Commit,
+    /// Group commits, flushing at the first commit once the given interval elapsed since the last flush.
+    /// The commits of the last interval may be lost if the machine crashes.
+    Periodic(Duration),
+}
+
+impl Durability {
+    /// is_due returns true if a commit made now has to be flushed, given the time of the last flush.
+    pub(crate) fn is_due(&self, last_sync: Instant) -> bool {
+        match self {
+            Durability::None => false,
+            Durability::Commit => true,
+            Durability::Periodic(interval) => last_sync.elapsed() >= *interval,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn is_due_works() {
+        use crate::durability::Durability;
+        use std::time::{Duration, Instant};
+
+        let now = Instant::now();
+        assert!(!Durability::None.is_due(now));
+        assert!(Durability::Commit.is_due(now));
+        assert!(!Durability::Periodic(Duration::from_secs(60)).is_due(now));
+        assert!(Durability::Periodic(Duration::from_secs(0)).is_due(now));
+    }
+}
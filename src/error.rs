@@ -1,3 +1,7 @@
+use crate::node_type::Offset;
+use std::path::PathBuf;
+use std::sync::PoisonError;
+
 #[derive(Debug)]
 pub enum Error {
     KeyNotFound,
@@ -7,6 +11,26 @@ pub enum Error {
     ValueOverflowError,
     TryFromSliceError(&'static str),
     UTF8Error,
+    InvalidHeader(&'static str),
+    VersionNotFound,
+    /// The page at the given offset does not match its checksum, it was damaged on disk.
+    Corruption {
+        offset: Offset,
+    },
+    /// The builder options are invalid, e.g. a b parameter too large for a node to fit in a page.
+    InvalidOptions(String),
+    /// There is no tree file at the given path, and it was not to be created.
+    TreeNotFound(PathBuf),
+    /// There is already a tree file at the given path, while error_if_exists was set.
+    TreeAlreadyExists(PathBuf),
+    /// The tree was opened for reading only, see BTreeBuilder::read_only.
+    ReadOnly,
+    /// The tree file at the given path is in use, either written by another process
+    /// or read by others while it was to be written.
+    Locked(PathBuf),
+    /// A write of the transaction failed partway leaving some of its pages partially written,
+    /// the transaction can only be rolled back.
+    TransactionAborted,
 }
 
 impl std::convert::From<std::io::Error> for Error {
@@ -14,3 +38,10 @@ impl std::convert::From<std::io::Error> for Error {
         Error::UnexpectedError
     }
 }
+
+/// A lock is poisoned once a thread panicked while holding it, e.g. in the middle of a write.
+impl<T> std::convert::From<PoisonError<T>> for Error {
+    fn from(_e: PoisonError<T>) -> Error {
+        Error::UnexpectedError
+    }
+}
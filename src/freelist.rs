@@ -0,0 +1,105 @@
+use crate::error::Error;
+use crate::node_type::Offset;
+use crate::page::Page;
+use crate::page_layout::{
+    FREELIST_CAPACITY, FREELIST_ENTRY_SIZE, FREELIST_HEADER_SIZE, FREELIST_NEXT_OFFSET,
+    FREELIST_NUM_ENTRIES_OFFSET, FREELIST_PAGE_TYPE, NODE_TYPE_OFFSET, PAGE_SIZE, PTR_SIZE,
+};
+use std::convert::TryFrom;
+
+/// FreelistPage is a trunk page of the free list, persisting the offsets of pages which are not in use.
+/// Trunk pages are linked together into a chain, starting at the page recorded along with the committed root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FreelistPage {
+    /// The next trunk page in the chain, None for the last page.
+    pub next: Option<Offset>,
+    /// The free pages along with the version whose commit released them, zero if they are not referenced
+    /// by the root of any version.
+    pub entries: Vec<(Offset, usize)>,
+}
+
+impl FreelistPage {
+    pub fn new(next: Option<Offset>, entries: Vec<(Offset, usize)>) -> FreelistPage {
+        FreelistPage { next, entries }
+    }
+}
+
+/// Implement TryFrom<Page> for FreelistPage allowing for easier
+/// deserialization of data from a Page.
+impl TryFrom<Page> for FreelistPage {
+    type Error = Error;
+    fn try_from(page: Page) -> Result<FreelistPage, Error> {
+        if page.get_data()[NODE_TYPE_OFFSET] != FREELIST_PAGE_TYPE {
+            return Err(Error::UnexpectedError);
+        }
+        // The chain ends with a pointer to offset zero - the superblock.
+        let next = match page.get_value_from_offset(FREELIST_NEXT_OFFSET)? {
+            0 => None,
+            next => Some(Offset(next)),
+        };
+        let num_entries = page.get_value_from_offset(FREELIST_NUM_ENTRIES_OFFSET)?;
+        if num_entries > FREELIST_CAPACITY {
+            return Err(Error::UnexpectedError);
+        }
+        let mut entries = Vec::with_capacity(num_entries);
+        for i in 0..num_entries {
+            let entry_offset = FREELIST_HEADER_SIZE + i * FREELIST_ENTRY_SIZE;
+            let offset = page.get_value_from_offset(entry_offset)?;
+            let version = page.get_value_from_offset(entry_offset + PTR_SIZE)?;
+            entries.push((Offset(offset), version));
+        }
+        Ok(FreelistPage::new(next, entries))
+    }
+}
+
+/// Implement TryFrom<&FreelistPage> for Page allowing for easier
+/// serialization of a freelist trunk page.
+impl TryFrom<&FreelistPage> for Page {
+    type Error = Error;
+    fn try_from(freelist: &FreelistPage) -> Result<Page, Error> {
+        if freelist.entries.len() > FREELIST_CAPACITY {
+            return Err(Error::UnexpectedError);
+        }
+        let mut page = Page::new([0x00; PAGE_SIZE]);
+        page.write_bytes_at_offset(&[FREELIST_PAGE_TYPE], NODE_TYPE_OFFSET, 1)?;
+        let Offset(next) = freelist.next.clone().unwrap_or(Offset(0));
+        page.write_value_at_offset(FREELIST_NEXT_OFFSET, next)?;
+        page.write_value_at_offset(FREELIST_NUM_ENTRIES_OFFSET, freelist.entries.len())?;
+        for (i, (Offset(offset), version)) in freelist.entries.iter().enumerate() {
+            let entry_offset = FREELIST_HEADER_SIZE + i * FREELIST_ENTRY_SIZE;
+            page.write_value_at_offset(entry_offset, *offset)?;
+            page.write_value_at_offset(entry_offset + PTR_SIZE, *version)?;
+        }
+        Ok(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn freelist_page_to_page_works() -> Result<(), Error> {
+        use crate::freelist::FreelistPage;
+        use crate::node_type::Offset;
+        use crate::page::Page;
+        use crate::page_layout::{FREELIST_CAPACITY, PAGE_SIZE};
+        use std::convert::TryFrom;
+
+        let full = (1..=FREELIST_CAPACITY)
+            .map(|i| (Offset(i * PAGE_SIZE), i % 3))
+            .collect();
+        for freelist in [
+            FreelistPage::new(Some(Offset(PAGE_SIZE * 7)), full),
+            FreelistPage::new(None, vec![(Offset(PAGE_SIZE), 0)]),
+            FreelistPage::new(None, vec![]),
+        ] {
+            // Serialize data.
+            let page = Page::try_from(&freelist)?;
+            // Deserialize back the page.
+            let res = FreelistPage::try_from(page)?;
+            assert_eq!(res, freelist);
+        }
+        Ok(())
+    }
+}
@@ -1,8 +1,20 @@
 pub mod btree;
+pub mod cache;
+mod checksum;
+mod compact;
+pub mod durability;
 pub mod error;
+mod freelist;
+pub mod mmap;
 pub mod node;
 pub mod node_type;
+mod overflow;
 pub mod page;
 mod page_layout;
 mod pager;
+pub mod range;
+pub mod snapshot;
+pub mod storage;
+mod superblock;
+pub mod transaction;
 mod wal;
@@ -0,0 +1,207 @@
+use crate::error::Error;
+use crate::node_type::Offset;
+use crate::page::Page;
+use crate::storage::{Access, Backend, FileBackend, FileStorage, PageStore, Storage, PAGE_SIZE};
+use memmap::{Mmap, MmapOptions};
+use std::cmp;
+use std::path::Path;
+use std::sync::Arc;
+
+/// MmapBackend stores trees in files like FileBackend but reads their pages straight out of a memory map
+/// of the file rather than with a system call per read, the pages read borrow the map instead of copying it.
+/// Writes still go to the file and wals are not mapped.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MmapBackend;
+
+impl Backend for MmapBackend {
+    fn open_pages(&self, path: &Path, access: Access) -> Result<Box<dyn PageStore>, Error> {
+        Ok(Box::new(MmapStorage::open(path, access)?))
+    }
+
+    fn open_log(&self, path: &Path, access: Access) -> Result<Box<dyn Storage>, Error> {
+        FileBackend.open_log(path, access)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        FileBackend.exists(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        FileBackend.rename(from, to)
+    }
+}
+
+/// MmapStorage is a file of pages read out of its memory map. The map is only replaced by writes:
+/// once a page is allocated past the end of the map the file is grown ahead of the allocated pages,
+/// doubling its length, and mapped anew. Reads never remap, a page is readable once allocated.
+/// Pages read keep the map they were read from alive, even once it was replaced.
+/// The file is trimmed to the allocated pages once the store is dropped.
+struct MmapStorage {
+    file: FileStorage,
+    access: Access,
+    /// The number of pages allocated, the file might be longer once grown ahead of them.
+    num_pages: usize,
+    /// The map of the whole file, None while the file is empty.
+    map: Option<Arc<Mmap>>,
+}
+
+impl MmapStorage {
+    fn open(path: &Path, access: Access) -> Result<MmapStorage, Error> {
+        let file = FileStorage::open(path, access)?;
+        // Round up so a partially written trailing page is never overwritten.
+        let num_pages = file.size()?.div_ceil(PAGE_SIZE);
+        let mut storage = MmapStorage {
+            file,
+            access,
+            num_pages,
+            map: None,
+        };
+        storage.remap()?;
+        Ok(storage)
+    }
+
+    /// remap maps the file up to its current length.
+    fn remap(&mut self) -> Result<(), Error> {
+        self.map = None;
+        if self.file.size()? == 0 {
+            return Ok(());
+        }
+        // Safety: the file is locked while it is open, thus it is neither truncated nor written under the map
+        // by another process. Pages read from the map are the pages of committed roots which are never written
+        // in place nor truncated, pages are only reused or truncated once they are no longer read.
+        self.map = Some(Arc::new(unsafe {
+            MmapOptions::new().map(self.file.file())?
+        }));
+        Ok(())
+    }
+
+    /// mapped_len returns the number of bytes mapped.
+    fn mapped_len(&self) -> usize {
+        self.map.as_ref().map_or(0, |map| map.len())
+    }
+}
+
+impl PageStore for MmapStorage {
+    fn read_page(&self, offset: &Offset) -> Result<Page, Error> {
+        match &self.map {
+            Some(map) if offset.0 < self.num_pages * PAGE_SIZE => map_page(map, offset.0),
+            _ => Err(Error::UnexpectedError),
+        }
+    }
+
+    fn write_page(&mut self, offset: &Offset, page: &Page) -> Result<(), Error> {
+        if !offset.0.is_multiple_of(PAGE_SIZE) || offset.0 >= self.num_pages * PAGE_SIZE {
+            return Err(Error::UnexpectedError);
+        }
+        self.file.write_at(page.get_data(), offset.0)
+    }
+
+    fn allocate_page(&mut self) -> Result<Offset, Error> {
+        let offset = Offset(self.num_pages * PAGE_SIZE);
+        let len = (self.num_pages + 1) * PAGE_SIZE;
+        if len > self.mapped_len() {
+            let grown = cmp::max(len, self.mapped_len() * 2);
+            // The map is dropped first, some platforms (e.g. Windows) refuse to resize a mapped file.
+            self.map = None;
+            let res = self.file.set_size(grown);
+            self.remap()?;
+            res?;
+        }
+        self.num_pages += 1;
+        Ok(offset)
+    }
+
+    fn num_pages(&self) -> usize {
+        self.num_pages
+    }
+
+    fn truncate(&mut self, num_pages: usize) -> Result<(), Error> {
+        // Drop the map first as the file is truncated under it.
+        self.map = None;
+        self.file.set_size(num_pages * PAGE_SIZE)?;
+        self.num_pages = num_pages;
+        self.remap()
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.file.sync()
+    }
+
+    fn try_lock(&self, shared: bool) -> Result<bool, Error> {
+        self.file.try_lock(shared)
+    }
+}
+
+/// map_page returns the page at the given offset of the map, borrowing it from the map.
+#[cfg(unix)]
+fn map_page(map: &Arc<Mmap>, offset: usize) -> Result<Page, Error> {
+    Page::mapped(Arc::clone(map), offset)
+}
+
+/// Other platforms (e.g. Windows) refuse to resize a file while a view of it is mapped,
+/// thus pages are copied out of the map there so that it is dropped before the file grows.
+#[cfg(not(unix))]
+fn map_page(map: &Arc<Mmap>, offset: usize) -> Result<Page, Error> {
+    let mut data = [0x00; PAGE_SIZE];
+    data.copy_from_slice(Page::mapped(Arc::clone(map), offset)?.get_data());
+    Ok(Page::new(data))
+}
+
+impl Drop for MmapStorage {
+    fn drop(&mut self) {
+        let len = self.num_pages * PAGE_SIZE;
+        if self.access == Access::ReadWrite && self.mapped_len() > len {
+            self.map = None;
+            let _ = self.file.set_size(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn mmap_storage_grows_ahead_of_pages() -> Result<(), Error> {
+        use crate::mmap::MmapBackend;
+        use crate::node_type::Offset;
+        use crate::page::Page;
+        use crate::storage::{Access, Backend, PAGE_SIZE};
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_mmap_storage")?;
+        let path = Path::new("/tmp/btree_mmap_storage/db");
+        let _ = fs::remove_file(path);
+        let mut pages = MmapBackend.open_pages(path, Access::ReadWrite)?;
+        let page = Page::new([0xAB; PAGE_SIZE]);
+        for i in 0..3 {
+            assert_eq!(pages.allocate_page()?, Offset(i * PAGE_SIZE));
+            pages.write_page(&Offset(i * PAGE_SIZE), &page)?;
+            assert_eq!(
+                pages.read_page(&Offset(i * PAGE_SIZE))?.get_data(),
+                page.get_data()
+            );
+        }
+        // Pages are read straight out of the map, a page read before the map was replaced stays readable.
+        let first = pages.read_page(&Offset(0))?;
+        #[cfg(unix)]
+        assert_eq!(
+            first.get_data().as_ptr(),
+            pages.read_page(&Offset(0))?.get_data().as_ptr()
+        );
+        assert_eq!(pages.allocate_page()?, Offset(3 * PAGE_SIZE));
+        assert_eq!(pages.allocate_page()?, Offset(4 * PAGE_SIZE));
+        assert_eq!(first.get_data(), page.get_data());
+        // The file was doubled ahead of the fifth page, reads past the allocated pages fail.
+        assert_eq!(fs::metadata(path)?.len(), 8 * PAGE_SIZE as u64);
+        assert!(pages.read_page(&Offset(5 * PAGE_SIZE)).is_err());
+
+        // The file is trimmed to the allocated pages once dropped.
+        drop(pages);
+        assert_eq!(fs::metadata(path)?.len(), 5 * PAGE_SIZE as u64);
+        let pages = MmapBackend.open_pages(path, Access::ReadOnly)?;
+        assert_eq!(pages.num_pages(), 5);
+        Ok(())
+    }
+}
@@ -2,9 +2,11 @@ use crate::error::Error;
 use crate::node_type::{Key, KeyValuePair, NodeType, Offset};
 use crate::page::Page;
 use crate::page_layout::{
-    FromByte, INTERNAL_NODE_HEADER_SIZE, INTERNAL_NODE_NUM_CHILDREN_OFFSET, IS_ROOT_OFFSET,
-    KEY_SIZE, LEAF_NODE_HEADER_SIZE, LEAF_NODE_NUM_PAIRS_OFFSET, NODE_TYPE_OFFSET,
-    PARENT_POINTER_OFFSET, PTR_SIZE, VALUE_SIZE,
+    internal_cell_size, FromByte, CELL_POINTER_SIZE, COMMON_NODE_HEADER_SIZE,
+    INTERNAL_NODE_HEADER_SIZE, INTERNAL_NODE_NUM_CHILDREN_OFFSET, INTERNAL_NODE_NUM_CHILDREN_SIZE,
+    IS_ROOT_OFFSET, KEY_LEN_SIZE, LEAF_NODE_HEADER_SIZE, LEAF_NODE_NUM_PAIRS_OFFSET,
+    LEAF_NODE_NUM_PAIRS_SIZE, NODE_TYPE_OFFSET, OVERFLOW_FLAG, PARENT_POINTER_OFFSET, PTR_SIZE,
+    USABLE_PAGE_SIZE, VALUE_LEN_SIZE,
 };
 use std::convert::TryFrom;
 use std::str;
@@ -27,18 +29,89 @@ impl Node {
         }
     }
 
+    /// size returns the number of bytes the node occupies once written to a page.
+    pub fn size(&self) -> usize {
+        match &self.node_type {
+            NodeType::Internal(children, keys) => {
+                INTERNAL_NODE_HEADER_SIZE
+                    + children.len() * PTR_SIZE
+                    + keys
+                        .iter()
+                        .map(|Key(key)| internal_cell_size(key.len()))
+                        .sum::<usize>()
+            }
+            NodeType::Leaf(pairs) => {
+                LEAF_NODE_HEADER_SIZE + pairs.iter().map(KeyValuePair::cell_size).sum::<usize>()
+            }
+            NodeType::Unexpected => COMMON_NODE_HEADER_SIZE,
+        }
+    }
+
+    /// split_index returns the index at which to split the node so that both halves
+    /// occupy about the same number of bytes while each fits a page of a tree of the given b parameter.
+    /// Leaves keep at least one pair on either side while internal nodes keep at least one key
+    /// (and two children) on either side of the median moved up to the parent.
+    pub fn split_index(&self, b: usize) -> Result<usize, Error> {
+        // The size and the number of keys of a half.
+        type Half = (usize, usize);
+        // Both halves for every index the node can be split at.
+        let (halves, max_keys): (Vec<(usize, Half, Half)>, usize) = match &self.node_type {
+            NodeType::Internal(children, keys) => {
+                let cells: Vec<usize> = keys
+                    .iter()
+                    .map(|Key(key)| internal_cell_size(key.len()))
+                    .collect();
+                let halves = (2..keys.len())
+                    .map(|idx| {
+                        let left = INTERNAL_NODE_HEADER_SIZE
+                            + idx * PTR_SIZE
+                            + cells[..idx - 1].iter().sum::<usize>();
+                        let right = INTERNAL_NODE_HEADER_SIZE
+                            + (children.len() - idx) * PTR_SIZE
+                            + cells[idx..].iter().sum::<usize>();
+                        (idx, (left, idx - 1), (right, keys.len() - idx))
+                    })
+                    .collect();
+                (halves, 2 * b - 1)
+            }
+            NodeType::Leaf(pairs) => {
+                let cells: Vec<usize> = pairs.iter().map(KeyValuePair::cell_size).collect();
+                let halves = (1..pairs.len())
+                    .map(|idx| {
+                        let left = LEAF_NODE_HEADER_SIZE + cells[..idx].iter().sum::<usize>();
+                        let right = LEAF_NODE_HEADER_SIZE + cells[idx..].iter().sum::<usize>();
+                        (idx, (left, idx), (right, pairs.len() - idx))
+                    })
+                    .collect();
+                (halves, 2 * b)
+            }
+            NodeType::Unexpected => return Err(Error::UnexpectedError),
+        };
+        let fits = |(size, num_keys): Half| size <= USABLE_PAGE_SIZE && num_keys <= max_keys;
+        halves
+            .into_iter()
+            .filter(|&(_, left, right)| fits(left) && fits(right))
+            .min_by_key(|&(_, (left, _), (right, _))| left.abs_diff(right))
+            .map(|(idx, _, _)| idx)
+            .ok_or(Error::UnexpectedError)
+    }
+
     /// split creates a sibling node from a given node by splitting the node in two around a median.
-    /// split will split the child at b leaving the [0, b-1] keys
-    /// while moving the set of [b, 2b-1] keys to the sibling.
-    pub fn split(&mut self, b: usize) -> Result<(Key, Node), Error> {
+    /// split will split the node at idx leaving the [0, idx-1] keys (or children)
+    /// while moving the rest to the sibling, e.g. splitting a full node at b
+    /// moves the set of [b, 2b-1] keys to the sibling.
+    pub fn split(&mut self, idx: usize) -> Result<(Key, Node), Error> {
         match self.node_type {
             NodeType::Internal(ref mut children, ref mut keys) => {
+                if idx == 0 || idx > keys.len() {
+                    return Err(Error::UnexpectedError);
+                }
                 // Populate siblings keys.
-                let mut sibling_keys = keys.split_off(b - 1);
+                let mut sibling_keys = keys.split_off(idx - 1);
                 // Pop median key - to be added to the parent..
                 let median_key = sibling_keys.remove(0);
                 // Populate siblings children.
-                let sibling_children = children.split_off(b);
+                let sibling_children = children.split_off(idx);
                 Ok((
                     median_key,
                     Node::new(
@@ -49,10 +122,13 @@ impl Node {
                 ))
             }
             NodeType::Leaf(ref mut pairs) => {
+                if idx == 0 || idx > pairs.len() {
+                    return Err(Error::UnexpectedError);
+                }
                 // Populate siblings pairs.
-                let sibling_pairs = pairs.split_off(b);
+                let sibling_pairs = pairs.split_off(idx);
                 // Pop median key.
-                let median_pair = pairs.get(b - 1).ok_or(Error::UnexpectedError)?.clone();
+                let median_pair = pairs.get(idx - 1).ok_or(Error::UnexpectedError)?.clone();
 
                 Ok((
                     Key(median_pair.key),
@@ -76,16 +152,18 @@ impl TryFrom<Page> for Node {
         let raw = page.get_data();
         let node_type = NodeType::from(raw[NODE_TYPE_OFFSET]);
         let is_root = raw[IS_ROOT_OFFSET].from_byte();
-        let parent_offset: Option<Offset>;
-        if is_root {
-            parent_offset = None;
+        let parent_offset = if is_root {
+            None
         } else {
-            parent_offset = Some(Offset(page.get_value_from_offset(PARENT_POINTER_OFFSET)?));
-        }
+            Some(Offset(page.get_value_from_offset(PARENT_POINTER_OFFSET)?))
+        };
 
         match node_type {
             NodeType::Internal(mut children, mut keys) => {
-                let num_children = page.get_value_from_offset(INTERNAL_NODE_NUM_CHILDREN_OFFSET)?;
+                let num_children = page.get_sized_value_from_offset(
+                    INTERNAL_NODE_NUM_CHILDREN_OFFSET,
+                    INTERNAL_NODE_NUM_CHILDREN_SIZE,
+                )?;
                 let mut offset = INTERNAL_NODE_HEADER_SIZE;
                 for _i in 1..=num_children {
                     let child_offset = page.get_value_from_offset(offset)?;
@@ -95,14 +173,17 @@ impl TryFrom<Page> for Node {
 
                 // Number of keys is always one less than the number of children (i.e. branching factor)
                 for _i in 1..num_children {
-                    let key_raw = page.get_ptr_from_offset(offset, KEY_SIZE);
+                    let cell_offset =
+                        page.get_sized_value_from_offset(offset, CELL_POINTER_SIZE)?;
+                    offset += CELL_POINTER_SIZE;
+
+                    let key_len = page.get_sized_value_from_offset(cell_offset, KEY_LEN_SIZE)?;
+                    let key_raw = page.get_ptr_from_offset(cell_offset + KEY_LEN_SIZE, key_len);
                     let key = match str::from_utf8(key_raw) {
                         Ok(key) => key,
                         Err(_) => return Err(Error::UTF8Error),
                     };
-                    offset += KEY_SIZE;
-                    // Trim leading or trailing zeros.
-                    keys.push(Key(key.trim_matches(char::from(0)).to_string()));
+                    keys.push(Key(key.to_string()));
                 }
                 Ok(Node::new(
                     NodeType::Internal(children, keys),
@@ -112,30 +193,45 @@ impl TryFrom<Page> for Node {
             }
 
             NodeType::Leaf(mut pairs) => {
-                let mut offset = LEAF_NODE_NUM_PAIRS_OFFSET;
-                let num_keys_val_pairs = page.get_value_from_offset(offset)?;
-                offset = LEAF_NODE_HEADER_SIZE;
+                let num_keys_val_pairs = page.get_sized_value_from_offset(
+                    LEAF_NODE_NUM_PAIRS_OFFSET,
+                    LEAF_NODE_NUM_PAIRS_SIZE,
+                )?;
+                let mut offset = LEAF_NODE_HEADER_SIZE;
 
                 for _i in 0..num_keys_val_pairs {
-                    let key_raw = page.get_ptr_from_offset(offset, KEY_SIZE);
+                    let mut cell_offset =
+                        page.get_sized_value_from_offset(offset, CELL_POINTER_SIZE)?;
+                    offset += CELL_POINTER_SIZE;
+
+                    let key_len = page.get_sized_value_from_offset(cell_offset, KEY_LEN_SIZE)?;
+                    cell_offset += KEY_LEN_SIZE;
+                    let mut value_len =
+                        page.get_sized_value_from_offset(cell_offset, VALUE_LEN_SIZE)?;
+                    cell_offset += VALUE_LEN_SIZE;
+                    // The top bit of the value length marks a value continued in overflow pages.
+                    let has_overflow = value_len & OVERFLOW_FLAG != 0;
+                    value_len &= !OVERFLOW_FLAG;
+
+                    let key_raw = page.get_ptr_from_offset(cell_offset, key_len);
                     let key = match str::from_utf8(key_raw) {
                         Ok(key) => key,
                         Err(_) => return Err(Error::UTF8Error),
                     };
-                    offset += KEY_SIZE;
+                    cell_offset += key_len;
 
-                    let value_raw = page.get_ptr_from_offset(offset, VALUE_SIZE);
+                    let value_raw = page.get_ptr_from_offset(cell_offset, value_len);
                     let value = match str::from_utf8(value_raw) {
                         Ok(val) => val,
                         Err(_) => return Err(Error::UTF8Error),
                     };
-                    offset += VALUE_SIZE;
+                    cell_offset += value_len;
 
-                    // Trim leading or trailing zeros.
-                    pairs.push(KeyValuePair::new(
-                        key.trim_matches(char::from(0)).to_string(),
-                        value.trim_matches(char::from(0)).to_string(),
-                    ))
+                    let mut pair = KeyValuePair::new(key.to_string(), value.to_string());
+                    if has_overflow {
+                        pair.overflow = Some(Offset(page.get_value_from_offset(cell_offset)?));
+                    }
+                    pairs.push(pair)
                 }
                 Ok(Node::new(NodeType::Leaf(pairs), is_root, parent_offset))
             }
@@ -146,17 +242,16 @@ impl TryFrom<Page> for Node {
 }
 
 ////////////////////
-///              ///
-///  Unit Tests. ///
-///              ///
+//                //
+//  Unit Tests.   //
+//                //
 ////////////////////
 
 #[cfg(test)]
 mod tests {
     use crate::error::Error;
     use crate::node::{
-        Node, Page, INTERNAL_NODE_HEADER_SIZE, KEY_SIZE, LEAF_NODE_HEADER_SIZE, PTR_SIZE,
-        VALUE_SIZE,
+        Node, Page, CELL_POINTER_SIZE, INTERNAL_NODE_HEADER_SIZE, LEAF_NODE_HEADER_SIZE, PTR_SIZE,
     };
     use crate::node_type::{Key, NodeType};
     use crate::page_layout::PAGE_SIZE;
@@ -164,32 +259,43 @@ mod tests {
 
     #[test]
     fn page_to_node_works_for_leaf_node() -> Result<(), Error> {
-        const DATA_LEN: usize = LEAF_NODE_HEADER_SIZE + KEY_SIZE + VALUE_SIZE;
-        let page_data: [u8; DATA_LEN] = [
+        use crate::node_type::KeyValuePair;
+        const HEADER_LEN: usize = LEAF_NODE_HEADER_SIZE + CELL_POINTER_SIZE;
+        let header_data: [u8; HEADER_LEN] = [
             0x01, // Is-Root byte.
             0x02, // Leaf Node type byte.
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Parent offset.
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // Number of Key-Value pairs.
-            0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x00, 0x00, 0x00, 0x00, 0x00, // "hello"
-            0x77, 0x6f, 0x72, 0x6c, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, // "world"
+            0x0F, 0xF0, // Pointer to cell #0 at 4080.
+        ];
+        let cell_data: [u8; 16] = [
+            0x00, 0x05, // Key length.
+            0x00, 0x00, 0x00, 0x05, // Value length.
+            0x68, 0x65, 0x6c, 0x6c, 0x6f, // "hello"
+            0x77, 0x6f, 0x72, 0x6c, 0x64, // "world"
         ];
-        let junk: [u8; PAGE_SIZE - DATA_LEN] = [0x00; PAGE_SIZE - DATA_LEN];
         let mut page = [0x00; PAGE_SIZE];
-        for (to, from) in page.iter_mut().zip(page_data.iter().chain(junk.iter())) {
-            *to = *from
-        }
+        page[..HEADER_LEN].clone_from_slice(&header_data);
+        page[PAGE_SIZE - cell_data.len()..].clone_from_slice(&cell_data);
 
         let node = Node::try_from(Page::new(page))?;
 
-        assert_eq!(node.is_root, true);
+        assert!(node.is_root);
+        assert_eq!(
+            node.node_type,
+            NodeType::Leaf(vec![KeyValuePair::new(
+                "hello".to_string(),
+                "world".to_string()
+            )])
+        );
         Ok(())
     }
 
     #[test]
     fn page_to_node_works_for_internal_node() -> Result<(), Error> {
         use crate::node_type::Key;
-        const DATA_LEN: usize = INTERNAL_NODE_HEADER_SIZE + 3 * PTR_SIZE + 2 * KEY_SIZE;
-        let page_data: [u8; DATA_LEN] = [
+        const HEADER_LEN: usize = INTERNAL_NODE_HEADER_SIZE + 3 * PTR_SIZE + 2 * CELL_POINTER_SIZE;
+        let header_data: [u8; HEADER_LEN] = [
             0x01, // Is-Root byte.
             0x01, // Internal Node type byte.
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Parent offset.
@@ -197,23 +303,25 @@ mod tests {
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, // 4096  (2nd Page)
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00, // 8192  (3rd Page)
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0x00, // 12288 (4th Page)
-            0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x00, 0x00, 0x00, 0x00, 0x00, // "hello"
-            0x77, 0x6f, 0x72, 0x6c, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, // "world"
+            0x0F, 0xF9, // Pointer to cell #0 at 4089.
+            0x0F, 0xF2, // Pointer to cell #1 at 4082.
+        ];
+        let cell_data: [u8; 14] = [
+            0x00, 0x05, 0x77, 0x6f, 0x72, 0x6c, 0x64, // "world"
+            0x00, 0x05, 0x68, 0x65, 0x6c, 0x6c, 0x6f, // "hello"
         ];
-        let junk: [u8; PAGE_SIZE - DATA_LEN] = [0x00; PAGE_SIZE - DATA_LEN];
 
-        // Concatenate the two arrays; page_data and junk.
+        // Place the header at the start of the page and the cells at its end.
         let mut page = [0x00; PAGE_SIZE];
-        for (to, from) in page.iter_mut().zip(page_data.iter().chain(junk.iter())) {
-            *to = *from
-        }
+        page[..HEADER_LEN].clone_from_slice(&header_data);
+        page[PAGE_SIZE - cell_data.len()..].clone_from_slice(&cell_data);
 
         let node = Node::try_from(Page::new(page))?;
 
         if let NodeType::Internal(_, keys) = node.node_type {
             assert_eq!(keys.len(), 2);
 
-            let Key(first_key) = match keys.get(0) {
+            let Key(first_key) = match keys.first() {
                 Some(key) => key,
                 None => return Err(Error::UnexpectedError),
             };
@@ -251,11 +359,13 @@ mod tests {
             NodeType::Leaf(vec![
                 KeyValuePair {
                     key: "foo".to_string(),
-                    value: "bar".to_string()
+                    value: "bar".to_string(),
+                    overflow: None,
                 },
                 KeyValuePair {
                     key: "lebron".to_string(),
-                    value: "james".to_string()
+                    value: "james".to_string(),
+                    overflow: None,
                 }
             ])
         );
@@ -1,5 +1,5 @@
 use crate::error::Error;
-use crate::page_layout::PTR_SIZE;
+use crate::page_layout::{leaf_cell_size, PTR_SIZE};
 use std::cmp::{Eq, Ord, Ordering, PartialOrd};
 use std::convert::From;
 use std::convert::TryFrom;
@@ -23,6 +23,9 @@ pub struct Key(pub String);
 pub struct KeyValuePair {
     pub key: String,
     pub value: String,
+    /// The offset of the overflow pages holding the rest of the value, for values too large to fit a leaf cell.
+    /// In that case value only holds the prefix of the value stored in the leaf.
+    pub(crate) overflow: Option<Offset>,
 }
 
 impl Ord for KeyValuePair {
@@ -39,13 +42,26 @@ impl PartialOrd for KeyValuePair {
 
 impl PartialEq for KeyValuePair {
     fn eq(&self, other: &Self) -> bool {
-        self.key == other.key && self.value == other.value
+        self.key == other.key && self.value == other.value && self.overflow == other.overflow
     }
 }
 
 impl KeyValuePair {
     pub fn new(key: String, value: String) -> KeyValuePair {
-        KeyValuePair { key, value }
+        KeyValuePair {
+            key,
+            value,
+            overflow: None,
+        }
+    }
+
+    /// cell_size returns the space taken by the pair in a leaf page.
+    pub fn cell_size(&self) -> usize {
+        let overflow_size = match self.overflow {
+            Some(_) => PTR_SIZE,
+            None => 0,
+        };
+        leaf_cell_size(self.key.len(), self.value.len()) + overflow_size
     }
 }
 
@@ -0,0 +1,92 @@
+use crate::error::Error;
+use crate::node_type::Offset;
+use crate::page::Page;
+use crate::page_layout::{
+    NODE_TYPE_OFFSET, OVERFLOW_DATA_LEN_OFFSET, OVERFLOW_DATA_SIZE, OVERFLOW_HEADER_SIZE,
+    OVERFLOW_NEXT_OFFSET, OVERFLOW_PAGE_TYPE, PAGE_SIZE,
+};
+use std::convert::TryFrom;
+
+/// OverflowPage holds a chunk of a value too large to fit a leaf cell.
+/// Overflow pages are linked together into a chain, starting at the leaf cell holding the value prefix.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OverflowPage {
+    /// The next page in the chain, None for the last page.
+    pub next: Option<Offset>,
+    pub data: Vec<u8>,
+}
+
+impl OverflowPage {
+    pub fn new(next: Option<Offset>, data: Vec<u8>) -> OverflowPage {
+        OverflowPage { next, data }
+    }
+}
+
+/// Implement TryFrom<Page> for OverflowPage allowing for easier
+/// deserialization of data from a Page.
+impl TryFrom<Page> for OverflowPage {
+    type Error = Error;
+    fn try_from(page: Page) -> Result<OverflowPage, Error> {
+        if page.get_data()[NODE_TYPE_OFFSET] != OVERFLOW_PAGE_TYPE {
+            return Err(Error::UnexpectedError);
+        }
+        // The chain ends with a pointer to offset zero - the superblock.
+        let next = match page.get_value_from_offset(OVERFLOW_NEXT_OFFSET)? {
+            0 => None,
+            next => Some(Offset(next)),
+        };
+        let data_len = page.get_value_from_offset(OVERFLOW_DATA_LEN_OFFSET)?;
+        if data_len > OVERFLOW_DATA_SIZE {
+            return Err(Error::UnexpectedError);
+        }
+        Ok(OverflowPage::new(
+            next,
+            page.get_ptr_from_offset(OVERFLOW_HEADER_SIZE, data_len)
+                .to_vec(),
+        ))
+    }
+}
+
+/// Implement TryFrom<&OverflowPage> for Page allowing for easier
+/// serialization of an overflow page.
+impl TryFrom<&OverflowPage> for Page {
+    type Error = Error;
+    fn try_from(overflow: &OverflowPage) -> Result<Page, Error> {
+        if overflow.data.len() > OVERFLOW_DATA_SIZE {
+            return Err(Error::ValueOverflowError);
+        }
+        let mut page = Page::new([0x00; PAGE_SIZE]);
+        page.write_bytes_at_offset(&[OVERFLOW_PAGE_TYPE], NODE_TYPE_OFFSET, 1)?;
+        let Offset(next) = overflow.next.clone().unwrap_or(Offset(0));
+        page.write_value_at_offset(OVERFLOW_NEXT_OFFSET, next)?;
+        page.write_value_at_offset(OVERFLOW_DATA_LEN_OFFSET, overflow.data.len())?;
+        page.write_bytes_at_offset(&overflow.data, OVERFLOW_HEADER_SIZE, overflow.data.len())?;
+        Ok(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn overflow_page_to_page_works() -> Result<(), Error> {
+        use crate::node_type::Offset;
+        use crate::overflow::OverflowPage;
+        use crate::page::Page;
+        use crate::page_layout::PAGE_SIZE;
+        use std::convert::TryFrom;
+
+        for overflow in [
+            OverflowPage::new(Some(Offset(PAGE_SIZE * 7)), vec![0x61; 4000]),
+            OverflowPage::new(None, "the end".as_bytes().to_vec()),
+        ] {
+            // Serialize data.
+            let page = Page::try_from(&overflow)?;
+            // Deserialize back the page.
+            let res = OverflowPage::try_from(page)?;
+            assert_eq!(res, overflow);
+        }
+        Ok(())
+    }
+}
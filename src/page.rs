@@ -1,27 +1,69 @@
+use crate::checksum::crc32c;
 use crate::error::Error;
 use crate::node::Node;
 use crate::node_type::{Key, NodeType, Offset};
 use crate::page_layout::{
-    ToByte, INTERNAL_NODE_HEADER_SIZE, INTERNAL_NODE_NUM_CHILDREN_OFFSET,
-    INTERNAL_NODE_NUM_CHILDREN_SIZE, IS_ROOT_OFFSET, KEY_SIZE, LEAF_NODE_HEADER_SIZE,
-    LEAF_NODE_NUM_PAIRS_OFFSET, LEAF_NODE_NUM_PAIRS_SIZE, NODE_TYPE_OFFSET, PAGE_SIZE,
-    PARENT_POINTER_OFFSET, PARENT_POINTER_SIZE, PTR_SIZE, VALUE_SIZE,
+    ToByte, CELL_POINTER_SIZE, CHECKSUM_OFFSET, INTERNAL_NODE_HEADER_SIZE,
+    INTERNAL_NODE_NUM_CHILDREN_OFFSET, INTERNAL_NODE_NUM_CHILDREN_SIZE, IS_ROOT_OFFSET,
+    KEY_LEN_SIZE, LEAF_NODE_HEADER_SIZE, LEAF_NODE_NUM_PAIRS_OFFSET, LEAF_NODE_NUM_PAIRS_SIZE,
+    MAX_KEY_SIZE, NODE_TYPE_OFFSET, OVERFLOW_FLAG, PAGE_SIZE, PARENT_POINTER_OFFSET,
+    PARENT_POINTER_SIZE, PTR_SIZE, USABLE_PAGE_SIZE, VALUE_LEN_SIZE,
 };
+use byteorder::{BigEndian, ByteOrder};
+use memmap::Mmap;
 use std::convert::TryFrom;
+use std::sync::Arc;
 
 /// Value is a wrapper for a value in the page.
 pub struct Value(pub usize);
 
 /// Page is a wrapper for a single page of memory
 /// providing some helpful helpers for quick access.
+/// Clones of a page share its bytes, these are copied once a shared page is modified.
+#[derive(Clone)]
 pub struct Page {
-    data: Box<[u8; PAGE_SIZE]>,
+    data: PageData,
+}
+
+/// PageData holds the bytes of a page, either its own or those found in a memory map of the tree file.
+#[derive(Clone)]
+enum PageData {
+    Owned(Arc<[u8; PAGE_SIZE]>),
+    /// The page found at the given offset of the map, which is kept alive by the page.
+    Mapped(Arc<Mmap>, usize),
 }
 
 impl Page {
     pub fn new(data: [u8; PAGE_SIZE]) -> Page {
         Page {
-            data: Box::new(data),
+            data: PageData::Owned(Arc::new(data)),
+        }
+    }
+
+    /// mapped returns the page found at the given offset of a memory map without copying it,
+    /// fails if the map ends before the page does.
+    pub fn mapped(map: Arc<Mmap>, offset: usize) -> Result<Page, Error> {
+        if offset
+            .checked_add(PAGE_SIZE)
+            .is_none_or(|end| end > map.len())
+        {
+            return Err(Error::UnexpectedError);
+        }
+        Ok(Page {
+            data: PageData::Mapped(map, offset),
+        })
+    }
+
+    /// update modifies the bytes of the page, these are copied first unless the page owns them alone.
+    fn update(&mut self, f: impl FnOnce(&mut [u8; PAGE_SIZE])) {
+        match &mut self.data {
+            PageData::Owned(data) => f(Arc::make_mut(data)),
+            PageData::Mapped(map, offset) => {
+                let mut data = [0x00; PAGE_SIZE];
+                data.copy_from_slice(&map[*offset..*offset + PAGE_SIZE]);
+                f(&mut data);
+                self.data = PageData::Owned(Arc::new(data));
+            }
         }
     }
 
@@ -32,18 +74,43 @@ impl Page {
             return Err(Error::UnexpectedError);
         }
         let bytes = value.to_be_bytes();
-        self.data[offset..offset + PTR_SIZE].clone_from_slice(&bytes);
+        self.update(|data| data[offset..offset + PTR_SIZE].clone_from_slice(&bytes));
         Ok(())
     }
 
     /// get_value_from_offset Fetches a value calculated as BigEndian, sized to usize.
     /// This function may error as the value might not fit into a usize.
     pub fn get_value_from_offset(&self, offset: usize) -> Result<usize, Error> {
-        let bytes = &self.data[offset..offset + PTR_SIZE];
+        let bytes = &self.get_data()[offset..offset + PTR_SIZE];
         let Value(res) = Value::try_from(bytes)?;
         Ok(res)
     }
 
+    /// write_sized_value_at_offset writes a given value (as BigEndian) occupying #size bytes
+    /// at a certain offset, overriding values at that offset.
+    pub fn write_sized_value_at_offset(
+        &mut self,
+        offset: usize,
+        value: usize,
+        size: usize,
+    ) -> Result<(), Error> {
+        if offset + size > PAGE_SIZE || (size < PTR_SIZE && value >> (size * 8) != 0) {
+            return Err(Error::UnexpectedError);
+        }
+        self.update(|data| {
+            BigEndian::write_uint(&mut data[offset..offset + size], value as u64, size)
+        });
+        Ok(())
+    }
+
+    /// get_sized_value_from_offset Fetches a BigEndian value occupying #size bytes.
+    pub fn get_sized_value_from_offset(&self, offset: usize, size: usize) -> Result<usize, Error> {
+        if offset + size > PAGE_SIZE {
+            return Err(Error::UnexpectedError);
+        }
+        Ok(BigEndian::read_uint(&self.get_data()[offset..offset + size], size) as usize)
+    }
+
     /// insert_bytes_at_offset pushes #size bytes from offset to end_offset
     /// inserts #size bytes from given slice.
     pub fn insert_bytes_at_offset(
@@ -54,13 +121,15 @@ impl Page {
         size: usize,
     ) -> Result<(), Error> {
         // This Should not occur - better verify.
-        if end_offset + size > self.data.len() {
+        if end_offset + size > PAGE_SIZE {
             return Err(Error::UnexpectedError);
         }
-        for idx in (offset..=end_offset).rev() {
-            self.data[idx + size] = self.data[idx]
-        }
-        self.data[offset..offset + size].clone_from_slice(bytes);
+        self.update(|data| {
+            for idx in (offset..=end_offset).rev() {
+                data[idx + size] = data[idx]
+            }
+            data[offset..offset + size].clone_from_slice(bytes);
+        });
         Ok(())
     }
 
@@ -71,18 +140,36 @@ impl Page {
         offset: usize,
         size: usize,
     ) -> Result<(), Error> {
-        self.data[offset..offset + size].clone_from_slice(bytes);
+        self.update(|data| data[offset..offset + size].clone_from_slice(bytes));
         Ok(())
     }
 
     /// get_ptr_from_offset Fetches a slice of bytes from certain offset and of certain size.
     pub fn get_ptr_from_offset(&self, offset: usize, size: usize) -> &[u8] {
-        &self.data[offset..offset + size]
+        &self.get_data()[offset..offset + size]
+    }
+
+    /// get_data returns the underlying bytes.
+    pub fn get_data(&self) -> &[u8] {
+        match &self.data {
+            PageData::Owned(data) => &data[..],
+            PageData::Mapped(map, offset) => &map[*offset..*offset + PAGE_SIZE],
+        }
     }
 
-    /// get_data returns the underlying array.
-    pub fn get_data(&self) -> [u8; PAGE_SIZE] {
-        *self.data
+    /// write_checksum records the checksum of the page content at the end of the page.
+    pub fn write_checksum(&mut self) {
+        self.update(|data| {
+            let checksum = crc32c(&data[..CHECKSUM_OFFSET]);
+            data[CHECKSUM_OFFSET..].clone_from_slice(&checksum.to_be_bytes());
+        });
+    }
+
+    /// verify_checksum checks the page content against the checksum recorded at the end of the page,
+    /// returning false if the page was damaged (or never written).
+    pub fn verify_checksum(&self) -> bool {
+        let checksum = crc32c(&self.get_data()[..CHECKSUM_OFFSET]);
+        self.get_data()[CHECKSUM_OFFSET..] == checksum.to_be_bytes()
     }
 }
 
@@ -109,70 +196,93 @@ impl TryFrom<&Node> for Page {
             };
         }
 
+        let mut page = Page::new(data);
         match &node.node_type {
             NodeType::Internal(child_offsets, keys) => {
-                data[INTERNAL_NODE_NUM_CHILDREN_OFFSET
-                    ..INTERNAL_NODE_NUM_CHILDREN_OFFSET + INTERNAL_NODE_NUM_CHILDREN_SIZE]
-                    .clone_from_slice(&child_offsets.len().to_be_bytes());
+                page.write_sized_value_at_offset(
+                    INTERNAL_NODE_NUM_CHILDREN_OFFSET,
+                    child_offsets.len(),
+                    INTERNAL_NODE_NUM_CHILDREN_SIZE,
+                )?;
 
                 let mut page_offset = INTERNAL_NODE_HEADER_SIZE;
                 for Offset(child_offset) in child_offsets {
-                    data[page_offset..page_offset + PTR_SIZE]
-                        .clone_from_slice(&child_offset.to_be_bytes());
+                    page.write_value_at_offset(page_offset, *child_offset)?;
                     page_offset += PTR_SIZE;
                 }
 
+                // The cell pointers follow the children while the cells are written
+                // backwards from the end of the page.
+                let mut cell_offset = USABLE_PAGE_SIZE;
                 for Key(key) in keys {
                     let key_bytes = key.as_bytes();
-                    let mut raw_key: [u8; KEY_SIZE] = [0x00; KEY_SIZE];
-                    if key_bytes.len() > KEY_SIZE {
+                    let cell_size = KEY_LEN_SIZE + key_bytes.len();
+                    if key_bytes.len() > MAX_KEY_SIZE
+                        || page_offset + CELL_POINTER_SIZE + cell_size > cell_offset
+                    {
                         return Err(Error::KeyOverflowError);
-                    } else {
-                        for (i, byte) in key_bytes.iter().enumerate() {
-                            raw_key[i] = *byte;
-                        }
                     }
-                    data[page_offset..page_offset + KEY_SIZE].clone_from_slice(&raw_key);
-                    page_offset += KEY_SIZE
+                    cell_offset -= cell_size;
+                    page.write_sized_value_at_offset(page_offset, cell_offset, CELL_POINTER_SIZE)?;
+                    page_offset += CELL_POINTER_SIZE;
+
+                    page.write_sized_value_at_offset(cell_offset, key_bytes.len(), KEY_LEN_SIZE)?;
+                    page.write_bytes_at_offset(
+                        key_bytes,
+                        cell_offset + KEY_LEN_SIZE,
+                        key_bytes.len(),
+                    )?;
                 }
             }
             NodeType::Leaf(kv_pairs) => {
                 // num of pairs
-                data[LEAF_NODE_NUM_PAIRS_OFFSET
-                    ..LEAF_NODE_NUM_PAIRS_OFFSET + LEAF_NODE_NUM_PAIRS_SIZE]
-                    .clone_from_slice(&kv_pairs.len().to_be_bytes());
+                page.write_sized_value_at_offset(
+                    LEAF_NODE_NUM_PAIRS_OFFSET,
+                    kv_pairs.len(),
+                    LEAF_NODE_NUM_PAIRS_SIZE,
+                )?;
 
                 let mut page_offset = LEAF_NODE_HEADER_SIZE;
+                let mut cell_offset = USABLE_PAGE_SIZE;
                 for pair in kv_pairs {
                     let key_bytes = pair.key.as_bytes();
-                    let mut raw_key: [u8; KEY_SIZE] = [0x00; KEY_SIZE];
-                    if key_bytes.len() > KEY_SIZE {
+                    if key_bytes.len() > MAX_KEY_SIZE {
                         return Err(Error::KeyOverflowError);
-                    } else {
-                        for (i, byte) in key_bytes.iter().enumerate() {
-                            raw_key[i] = *byte;
-                        }
                     }
-                    data[page_offset..page_offset + KEY_SIZE].clone_from_slice(&raw_key);
-                    page_offset += KEY_SIZE;
-
                     let value_bytes = pair.value.as_bytes();
-                    let mut raw_value: [u8; VALUE_SIZE] = [0x00; VALUE_SIZE];
-                    if value_bytes.len() > VALUE_SIZE {
+                    let cell_size = pair.cell_size() - CELL_POINTER_SIZE;
+                    if page_offset + CELL_POINTER_SIZE + cell_size > cell_offset
+                        || value_bytes.len() >= OVERFLOW_FLAG
+                    {
                         return Err(Error::ValueOverflowError);
-                    } else {
-                        for (i, byte) in value_bytes.iter().enumerate() {
-                            raw_value[i] = *byte;
-                        }
                     }
-                    data[page_offset..page_offset + VALUE_SIZE].clone_from_slice(&raw_value);
-                    page_offset += VALUE_SIZE;
+                    // Values continued in overflow pages are marked by the top bit of their length.
+                    let value_len = match pair.overflow {
+                        Some(_) => value_bytes.len() | OVERFLOW_FLAG,
+                        None => value_bytes.len(),
+                    };
+                    cell_offset -= cell_size;
+                    page.write_sized_value_at_offset(page_offset, cell_offset, CELL_POINTER_SIZE)?;
+                    page_offset += CELL_POINTER_SIZE;
+
+                    let mut offset = cell_offset;
+                    page.write_sized_value_at_offset(offset, key_bytes.len(), KEY_LEN_SIZE)?;
+                    offset += KEY_LEN_SIZE;
+                    page.write_sized_value_at_offset(offset, value_len, VALUE_LEN_SIZE)?;
+                    offset += VALUE_LEN_SIZE;
+                    page.write_bytes_at_offset(key_bytes, offset, key_bytes.len())?;
+                    offset += key_bytes.len();
+                    page.write_bytes_at_offset(value_bytes, offset, value_bytes.len())?;
+                    offset += value_bytes.len();
+                    if let Some(Offset(overflow_offset)) = pair.overflow {
+                        page.write_value_at_offset(offset, overflow_offset)?;
+                    }
                 }
             }
             NodeType::Unexpected => return Err(Error::UnexpectedError),
         }
 
-        Ok(Page::new(data))
+        Ok(page)
     }
 }
 
@@ -199,6 +309,24 @@ impl TryFrom<&[u8]> for Value {
 mod tests {
     use crate::error::Error;
 
+    #[test]
+    fn checksum_detects_damage() {
+        use crate::page::Page;
+        use crate::page_layout::PAGE_SIZE;
+
+        let mut data = [0x00; PAGE_SIZE];
+        data[..5].clone_from_slice("hello".as_bytes());
+        let mut page = Page::new(data);
+        // A page which was never written does not match its checksum.
+        assert!(!page.verify_checksum());
+        page.write_checksum();
+        assert!(page.verify_checksum());
+
+        data.copy_from_slice(page.get_data());
+        data[100] ^= 0x01;
+        assert!(!Page::new(data).verify_checksum());
+    }
+
     #[test]
     fn node_to_page_works_for_leaf_node() -> Result<(), Error> {
         use crate::node::Node;
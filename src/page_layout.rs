@@ -1,12 +1,19 @@
-use crate::btree::MAX_BRANCHING_FACTOR;
 use std::mem::size_of;
 
 /// A single page size.
-/// Each page represents a node in the BTree.
+/// Each page represents a node in the BTree, except for the first page of
+/// the file which is reserved for the superblock.
 pub const PAGE_SIZE: usize = 4096;
 
 pub const PTR_SIZE: usize = size_of::<usize>();
 
+/// Every page ends with a CRC32C checksum of the rest of the page (Four bytes),
+/// which is written whenever the page is written and verified whenever it is read.
+pub const CHECKSUM_SIZE: usize = 4;
+pub const CHECKSUM_OFFSET: usize = PAGE_SIZE - CHECKSUM_SIZE;
+/// The bytes of a page available to its content.
+pub const USABLE_PAGE_SIZE: usize = CHECKSUM_OFFSET;
+
 /// Common Node header layout (Ten bytes in total)
 pub const IS_ROOT_SIZE: usize = 1;
 pub const IS_ROOT_OFFSET: usize = 0;
@@ -17,40 +24,110 @@ pub const PARENT_POINTER_SIZE: usize = PTR_SIZE;
 pub const COMMON_NODE_HEADER_SIZE: usize = NODE_TYPE_SIZE + IS_ROOT_SIZE + PARENT_POINTER_SIZE;
 
 /// Leaf node header layout (Eighteen bytes in total)
-///
-/// Space for keys and values: PAGE_SIZE - LEAF_NODE_HEADER_SIZE = 4096 - 18 = 4078 bytes.
-/// Which leaves 4076 / keys_limit = 20 (ten for key and 10 for value).
 pub const LEAF_NODE_NUM_PAIRS_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
 pub const LEAF_NODE_NUM_PAIRS_SIZE: usize = PTR_SIZE;
 pub const LEAF_NODE_HEADER_SIZE: usize = COMMON_NODE_HEADER_SIZE + LEAF_NODE_NUM_PAIRS_SIZE;
 
 /// Internal header layout (Eighteen bytes in total)
-///
-// Space for children and keys: PAGE_SIZE - INTERNAL_NODE_HEADER_SIZE = 4096 - 18 = 4078 bytes.
 pub const INTERNAL_NODE_NUM_CHILDREN_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
 pub const INTERNAL_NODE_NUM_CHILDREN_SIZE: usize = PTR_SIZE;
 pub const INTERNAL_NODE_HEADER_SIZE: usize =
     COMMON_NODE_HEADER_SIZE + INTERNAL_NODE_NUM_CHILDREN_SIZE;
 
-/// On a 64 bit machine the maximum space to keep all of the pointer
-/// is 200 * 8 = 1600 bytes.
-#[allow(dead_code)]
-pub const MAX_SPACE_FOR_CHILDREN: usize = MAX_BRANCHING_FACTOR * PTR_SIZE;
+/// Slotted page layout.
+///
+/// Following the header (and the child offsets in the case of an internal node)
+/// a page holds an array of cell pointers, one per key, each pointing at a cell
+/// in the cell heap which grows backwards from the checksum at the end of the page.
+pub const CELL_POINTER_SIZE: usize = 2;
+
+/// Leaf cell layout: | Key length - 2 bytes | Value length - 4 bytes | Key | Value |
+/// Internal cell layout: | Key length - 2 bytes | Key |
+pub const KEY_LEN_SIZE: usize = 2;
+pub const VALUE_LEN_SIZE: usize = 4;
+pub const LEAF_CELL_HEADER_SIZE: usize = KEY_LEN_SIZE + VALUE_LEN_SIZE;
+pub const INTERNAL_CELL_HEADER_SIZE: usize = KEY_LEN_SIZE;
+
+/// Every page has room for at least four cells; This way splitting a full node
+/// always leaves enough room in either half for the cell which caused the split.
+pub const MIN_CELLS_PER_PAGE: usize = 4;
+/// (4092 - 18) / 4 = 1018 bytes including the cell pointer.
+pub const MAX_CELL_SIZE: usize = (USABLE_PAGE_SIZE - LEAF_NODE_HEADER_SIZE) / MIN_CELLS_PER_PAGE;
+/// A key has to fit a leaf cell whose value was moved to overflow pages
+/// (and thus an internal cell along with its child offset): 1018 - 16 = 1002 bytes.
+pub const MAX_KEY_SIZE: usize =
+    MAX_CELL_SIZE - CELL_POINTER_SIZE - LEAF_CELL_HEADER_SIZE - PTR_SIZE;
+
+/// Overflow pages.
+///
+/// A value which does not fit its leaf cell keeps a prefix of up to 128 bytes in the cell,
+/// the rest of it is written to a chain of overflow pages, whose first offset follows the prefix.
+/// Such cells are marked by the top bit of their value length.
+pub const MAX_OVERFLOW_PREFIX_SIZE: usize = 128;
+pub const OVERFLOW_FLAG: usize = 1 << (VALUE_LEN_SIZE * 8 - 1);
+
+/// Overflow page layout (Eighteen bytes of header)
+///
+/// | UNUSED 1-byte | PAGE-TYPE 1-byte | NEXT OVERFLOW PAGE OFFSET - 8 bytes | Data length - 8 bytes | Data |
+/// The last page in a chain points at offset zero (the superblock).
+pub const OVERFLOW_PAGE_TYPE: u8 = 0x04;
+pub const OVERFLOW_NEXT_OFFSET: usize = PARENT_POINTER_OFFSET;
+pub const OVERFLOW_DATA_LEN_OFFSET: usize = OVERFLOW_NEXT_OFFSET + PTR_SIZE;
+pub const OVERFLOW_HEADER_SIZE: usize = OVERFLOW_DATA_LEN_OFFSET + PTR_SIZE;
+pub const OVERFLOW_DATA_SIZE: usize = USABLE_PAGE_SIZE - OVERFLOW_HEADER_SIZE;
+
+/// Freelist trunk page layout (Eighteen bytes of header)
+///
+/// | UNUSED 1-byte | PAGE-TYPE 1-byte | NEXT TRUNK PAGE OFFSET - 8 bytes | Number of entries - 8 bytes |
+/// | Free page offset #0 - 8 bytes | Released by version #0 - 8 bytes | ...
+/// The last trunk page points at offset zero (the superblock).
+pub const FREELIST_PAGE_TYPE: u8 = 0x05;
+pub const FREELIST_NEXT_OFFSET: usize = PARENT_POINTER_OFFSET;
+pub const FREELIST_NUM_ENTRIES_OFFSET: usize = FREELIST_NEXT_OFFSET + PTR_SIZE;
+pub const FREELIST_HEADER_SIZE: usize = FREELIST_NUM_ENTRIES_OFFSET + PTR_SIZE;
+pub const FREELIST_ENTRY_SIZE: usize = PTR_SIZE * 2;
+pub const FREELIST_CAPACITY: usize =
+    (USABLE_PAGE_SIZE - FREELIST_HEADER_SIZE) / FREELIST_ENTRY_SIZE;
+
+/// leaf_cell_size returns the space taken by a key-value pair in a leaf page.
+pub const fn leaf_cell_size(key_size: usize, value_size: usize) -> usize {
+    CELL_POINTER_SIZE + LEAF_CELL_HEADER_SIZE + key_size + value_size
+}
 
-/// This leaves the keys of an internal node 2478 bytes:
-/// We use 1990 bytes for keys which leaves 488 bytes as junk.
-/// This means each key is limited to 12 bytes. (2476 / keys limit = ~12)
-/// Rounded down to 10 to accomodate the leave node.
-#[allow(dead_code)]
-pub const MAX_SPACE_FOR_KEYS: usize =
-    PAGE_SIZE - INTERNAL_NODE_HEADER_SIZE - MAX_SPACE_FOR_CHILDREN;
+/// internal_cell_size returns the space taken by a key in an internal page,
+/// not including the child offsets.
+pub const fn internal_cell_size(key_size: usize) -> usize {
+    CELL_POINTER_SIZE + INTERNAL_CELL_HEADER_SIZE + key_size
+}
 
-/// Key, Value sizes.
-pub const KEY_SIZE: usize = 10;
-pub const VALUE_SIZE: usize = 10;
+/// Superblock layout (One hundred and four bytes in total)
+///
+/// The superblock occupies the first page of the tree file and describes the
+/// layout constants the file was written with, followed by the last committed root,
+/// the oldest version of the tree which is still readable, the first freelist trunk page,
+/// the number of pages in use by the tree file, the version of the last commit
+/// and a random id tying the tree file to its wal.
+pub const SUPERBLOCK_OFFSET: usize = 0;
+pub const MAGIC: [u8; MAGIC_SIZE] = *b"BTREEDB\0";
+pub const MAGIC_OFFSET: usize = 0;
+pub const MAGIC_SIZE: usize = 8;
+pub const FORMAT_VERSION: usize = 9;
+pub const FORMAT_VERSION_OFFSET: usize = MAGIC_OFFSET + MAGIC_SIZE;
+pub const PAGE_SIZE_OFFSET: usize = FORMAT_VERSION_OFFSET + PTR_SIZE;
+pub const B_PARAMETER_OFFSET: usize = PAGE_SIZE_OFFSET + PTR_SIZE;
+pub const MAX_KEY_SIZE_OFFSET: usize = B_PARAMETER_OFFSET + PTR_SIZE;
+pub const MAX_CELL_SIZE_OFFSET: usize = MAX_KEY_SIZE_OFFSET + PTR_SIZE;
+pub const ROOT_OFFSET: usize = MAX_CELL_SIZE_OFFSET + PTR_SIZE;
+pub const OLDEST_VERSION_OFFSET: usize = ROOT_OFFSET + PTR_SIZE;
+pub const FREELIST_OFFSET: usize = OLDEST_VERSION_OFFSET + PTR_SIZE;
+pub const NUM_PAGES_OFFSET: usize = FREELIST_OFFSET + PTR_SIZE;
+pub const VERSION_OFFSET: usize = NUM_PAGES_OFFSET + PTR_SIZE;
+pub const ID_OFFSET: usize = VERSION_OFFSET + PTR_SIZE;
+pub const ID_SIZE: usize = 16;
 
 /// Wrappers for converting byte to bool and back.
 /// The convention used throughout the index file is: one is true; otherwise - false.
+#[allow(clippy::wrong_self_convention)]
 pub trait FromByte {
     fn from_byte(&self) -> bool;
 }
@@ -1,50 +1,549 @@
+use crate::cache::{CacheStats, PageCache};
 use crate::error::Error;
+use crate::freelist::FreelistPage;
 use crate::node_type::Offset;
 use crate::page::Page;
-use crate::page_layout::PAGE_SIZE;
-use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::io::{Read, Seek, SeekFrom};
+use crate::page_layout::{FREELIST_CAPACITY, PAGE_SIZE};
+use crate::storage::{Access, Backend, PageStore};
+use crate::wal::Record;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::mem;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// lock takes an advisory lock on the pager store which is held until it is closed,
+/// a shared lock for reading only and an exclusive one otherwise. Fails with Locked if the store is locked
+/// by another process, or by another pager of this process, such that the two cannot share it.
+fn lock(store: &dyn PageStore, path: &Path, shared: bool) -> Result<(), Error> {
+    match store.try_lock(shared)? {
+        true => Ok(()),
+        false => Err(Error::Locked(path.to_path_buf())),
+    }
+}
+
+/// trunks_needed returns the number of freelist trunk pages listing the given number of pages and free pages,
+/// trunk pages are taken from the free pages first which then no longer have to be listed.
+fn trunks_needed(entries: usize, free: usize) -> usize {
+    let mut trunks = 0;
+    while trunks * FREELIST_CAPACITY < entries + free - cmp::min(trunks, free) {
+        trunks += 1;
+    }
+    trunks
+}
 
 pub struct Pager {
-    file: File,
-    curser: usize,
+    /// The store holding the pages of the tree file, new pages are allocated by it.
+    store: Box<dyn PageStore>,
+    /// Pages which were released and can be reused by write_page.
+    free_pages: Vec<Offset>,
+    /// Pages released since the last commit, these might still be referenced
+    /// by the committed root and thus cannot be reused yet.
+    released_pages: Vec<Offset>,
+    /// Pages written since the last commit, these are not referenced by the committed root.
+    dirty_pages: HashSet<usize>,
+    /// The last root published to readers, a committed root is published once its record is logged.
+    root: Offset,
+    /// The version of the published root.
+    root_version: usize,
+    /// The version of the last committed root, roots are numbered from one in the order they were committed.
+    version: usize,
+    /// The oldest version whose pages were not reused.
+    oldest_version: usize,
+    /// The version of the last commit whose wal record was flushed to disk. The tree is recovered
+    /// from this commit after a crash, thus the pages released by later commits are not reused.
+    durable_version: usize,
+    /// Pages released by the commit of a given version which might still be referenced by a pinned root,
+    /// or by the durable root until that commit is flushed.
+    pinned_pages: Vec<(usize, Offset)>,
+    /// Pages which are free as of the commit of a given version, but are only reused once that commit
+    /// is flushed, e.g. the trunk pages of the freelist it replaced. These are never referenced by a root.
+    unflushed_pages: Vec<(usize, Offset)>,
+    /// The root of each pinned version along with the number of pins held on it,
+    /// pinned by readers sharing the pager.
+    pins: Mutex<BTreeMap<usize, (Offset, usize)>>,
+    /// Whether a version was unpinned since the released pages were last reclaimed.
+    unpinned: AtomicBool,
+    /// The trunk pages persisting the free pages as of the last commit from the head of the chain,
+    /// along with the pages they list.
+    freelist: Vec<(Offset, FreelistPage)>,
+    /// Whether the pager file was opened for reading only, writes fail with ReadOnly.
+    read_only: bool,
+    /// The pages read or written most recently, shared by readers.
+    cache: PageCache,
 }
 
 impl Pager {
-    pub fn new(path: &Path) -> Result<Pager, Error> {
-        let fd = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .truncate(true)
-            .open(path)?;
-
-        Ok(Pager {
-            file: fd,
-            curser: 0,
-        })
+    /// new creates an empty pager file, truncating an existing one unless it is locked.
+    pub fn new(backend: &dyn Backend, path: &Path) -> Result<Pager, Error> {
+        let mut store = backend.open_pages(path, Access::ReadWrite)?;
+        // The file might be in use, it is only truncated once locked.
+        lock(store.as_ref(), path, false)?;
+        store.truncate(0)?;
+        Ok(Pager::from_store(store))
+    }
+
+    /// open opens an existing pager file (creating it if missing) without truncating it.
+    /// New pages are appended after the last page found in the file.
+    pub fn open(backend: &dyn Backend, path: &Path) -> Result<Pager, Error> {
+        let store = backend.open_pages(path, Access::ReadWrite)?;
+        lock(store.as_ref(), path, false)?;
+        Ok(Pager::from_store(store))
+    }
+
+    /// open_read_only opens an existing pager file for reading only, writes fail.
+    /// Several read only pagers may share the file, though not with a writable one.
+    pub fn open_read_only(backend: &dyn Backend, path: &Path) -> Result<Pager, Error> {
+        let store = backend.open_pages(path, Access::ReadOnly)?;
+        lock(store.as_ref(), path, true)?;
+        let mut pager = Pager::from_store(store);
+        pager.read_only = true;
+        Ok(pager)
+    }
+
+    fn from_store(store: Box<dyn PageStore>) -> Pager {
+        Pager {
+            store,
+            free_pages: Vec::new(),
+            released_pages: Vec::new(),
+            dirty_pages: HashSet::new(),
+            root: Offset(0),
+            root_version: 0,
+            version: 0,
+            oldest_version: 1,
+            durable_version: 0,
+            pinned_pages: Vec::new(),
+            unflushed_pages: Vec::new(),
+            pins: Mutex::new(BTreeMap::new()),
+            unpinned: AtomicBool::new(false),
+            freelist: Vec::new(),
+            read_only: false,
+            cache: PageCache::new(0),
+        }
+    }
+
+    /// is_empty returns true if no page was ever written to the pager file.
+    pub fn is_empty(&self) -> bool {
+        self.store.num_pages() == 0
+    }
+
+    /// get_page reads the page at the given offset, fails with Corruption if it does not match its checksum.
+    /// Pages are read at their offset without moving a shared cursor, thus several threads may read at once.
+    pub fn get_page(&self, offset: &Offset) -> Result<Page, Error> {
+        // Cached pages were either verified once read or written by the pager itself.
+        if let Some(page) = self.cache.get(offset.0)? {
+            return Ok(page);
+        }
+        let page = self.read_page(offset)?;
+        if !page.verify_checksum() {
+            return Err(Error::Corruption {
+                offset: offset.clone(),
+            });
+        }
+        self.cache.insert(offset.0, page.clone())?;
+        Ok(page)
     }
 
-    pub fn get_page(&mut self, offset: &Offset) -> Result<Page, Error> {
-        let mut page: [u8; PAGE_SIZE] = [0x00; PAGE_SIZE];
-        self.file.seek(SeekFrom::Start(offset.0 as u64))?;
-        self.file.read_exact(&mut page)?;
-        Ok(Page::new(page))
+    /// read_page reads the page at the given offset without verifying its checksum.
+    pub fn read_page(&self, offset: &Offset) -> Result<Page, Error> {
+        self.store.read_page(offset)
     }
 
+    /// write_page writes the page to a free page if there is one,
+    /// otherwise to a page newly allocated by the store.
     pub fn write_page(&mut self, page: Page) -> Result<Offset, Error> {
-        self.file.seek(SeekFrom::Start(self.curser as u64))?;
-        self.file.write_all(&page.get_data())?;
-        let res = Offset(self.curser);
-        self.curser += PAGE_SIZE;
-        Ok(res)
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        // The pages of the versions unpinned by readers are reclaimed once needed.
+        if self.free_pages.is_empty() && *self.unpinned.get_mut() {
+            self.reclaim()?;
+        }
+        let offset = match self.free_pages.pop() {
+            Some(offset) => offset,
+            None => self.store.allocate_page()?,
+        };
+        // A page which could not be written is not referenced, it is left free.
+        if let Err(e) = self.write_page_at_offset(page, &offset) {
+            self.free_pages.push(offset);
+            return Err(e);
+        }
+        self.dirty_pages.insert(offset.0);
+        Ok(offset)
+    }
+
+    /// allocate_page allocates a page following the last page of the file without writing it,
+    /// e.g. to lay out a tree file in advance.
+    pub fn allocate_page(&mut self) -> Result<Offset, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.store.allocate_page()
+    }
+
+    /// is_dirty returns true if the page was written by write_page since the last commit.
+    pub fn is_dirty(&self, offset: &Offset) -> bool {
+        self.dirty_pages.contains(&offset.0)
+    }
+
+    /// release marks a page as no longer in use,
+    /// it is only reused by write_page once the next root is committed.
+    pub fn release(&mut self, offset: Offset) {
+        self.released_pages.push(offset);
+    }
+
+    /// commit makes the pages released up until a root was committed available for reuse once the commit
+    /// is flushed, unless they might still be referenced by a pinned root. The free pages are persisted along the way,
+    /// returning the record to be logged for the committed root. Readers keep reading the published root
+    /// until the committed one is published.
+    pub fn commit(&mut self, root: Offset) -> Result<Record, Error> {
+        self.version += 1;
+        let version = self.version;
+        self.pinned_pages.extend(
+            self.released_pages
+                .drain(..)
+                .map(|offset| (version, offset)),
+        );
+        self.dirty_pages.clear();
+        self.reclaim()?;
+        self.write_freelist(root)
+    }
+
+    /// write_freelist persists the free pages, including the ones still pinned, to the chain of freelist trunk pages.
+    /// Trunk pages are never written in place: the trunks at the tail of the previous chain which list pages that
+    /// are all still free are kept, the pages listed by the others are written to new trunks at the head of the chain.
+    /// Pages are reused from the head of the chain first, thus a commit mostly replaces the trunks at the head alone.
+    /// The replaced trunk pages are referenced by the previously logged record,
+    /// they are listed as free by the new chain and are only reused once the returned record is flushed.
+    fn write_freelist(&mut self, root: Offset) -> Result<Record, Error> {
+        // The version each page is listed with, zero if it is not referenced by the root of any version.
+        let listed: HashMap<usize, usize> = self
+            .free_pages
+            .iter()
+            .chain(self.unflushed_pages.iter().map(|(_, offset)| offset))
+            .map(|Offset(offset)| (*offset, 0))
+            .chain(
+                self.pinned_pages
+                    .iter()
+                    .map(|(version, Offset(offset))| (*offset, *version)),
+            )
+            .collect();
+        // A trunk listing a page which was pinned since and freed is kept, the page is freed once the chain is loaded.
+        let mut kept = self
+            .freelist
+            .iter()
+            .rev()
+            .take_while(|(_, trunk)| {
+                trunk.entries.iter().all(|(Offset(offset), version)| {
+                    listed.get(offset).is_some_and(|listed| listed <= version)
+                })
+            })
+            .count();
+        // Kept trunks listing free pages are replaced rather than extending the file for new trunks.
+        let (mut entries, mut free_pages, mut kept_free) = loop {
+            let (entries, free_pages, kept_free) = self.unlisted_pages(listed.clone(), kept);
+            if kept == 0
+                || kept_free.is_empty()
+                || trunks_needed(entries.len(), free_pages.len()) <= free_pages.len()
+            {
+                break (entries, free_pages, kept_free);
+            }
+            kept -= 1;
+        };
+        let kept_trunks = self.freelist.split_off(self.freelist.len() - kept);
+        let replaced = mem::replace(&mut self.freelist, kept_trunks);
+        entries.extend(replaced.iter().map(|(offset, _)| (offset.clone(), 0)));
+
+        // Free pages taken for trunk pages no longer have to be listed.
+        let mut trunks = vec![];
+        for _ in 0..trunks_needed(entries.len(), free_pages.len()) {
+            let trunk = match free_pages.pop() {
+                Some(offset) => offset,
+                None => self.store.allocate_page()?,
+            };
+            trunks.push(trunk);
+        }
+        entries.extend(free_pages.iter().map(|offset| (offset.clone(), 0)));
+        // The pages listed by the kept trunks are reused last.
+        kept_free.extend(free_pages);
+        self.free_pages = kept_free;
+
+        // The entries are listed from the tail of the chain, the pages reused first are listed by its head.
+        let mut next = self.freelist.first().map(|(offset, _)| offset.clone());
+        let mut written = vec![];
+        for (i, trunk) in trunks.into_iter().enumerate() {
+            let start = cmp::min(i * FREELIST_CAPACITY, entries.len());
+            let end = cmp::min(start + FREELIST_CAPACITY, entries.len());
+            let page = FreelistPage::new(next, entries[start..end].to_vec());
+            self.write_page_at_offset(Page::try_from(&page)?, &trunk)?;
+            next = Some(trunk.clone());
+            written.push((trunk, page));
+        }
+        written.reverse();
+        written.append(&mut self.freelist);
+        self.freelist = written;
+        let version = self.version;
+        self.unflushed_pages
+            .extend(replaced.into_iter().map(|(offset, _)| (version, offset)));
+        Ok(Record {
+            version: self.version,
+            root,
+            freelist: self
+                .freelist
+                .first()
+                .map_or(Offset(0), |(offset, _)| offset.clone()),
+            num_pages: self.store.num_pages(),
+            oldest_version: self.oldest_version,
+        })
+    }
+
+    /// unlisted_pages returns the pages which are not listed by the given number of trunks at the tail of the chain,
+    /// the pages listed along with their version and the free pages, followed by the free pages listed by these trunks.
+    /// The pages listed by the trunks which are not kept are left out, as these are replaced along with their trunks.
+    fn unlisted_pages(
+        &self,
+        mut listed: HashMap<usize, usize>,
+        kept: usize,
+    ) -> (Vec<(Offset, usize)>, Vec<Offset>, Vec<Offset>) {
+        let free: HashSet<usize> = self
+            .free_pages
+            .iter()
+            .map(|Offset(offset)| *offset)
+            .collect();
+        let mut kept_free = vec![];
+        for (_, trunk) in self.freelist.iter().rev().take(kept) {
+            for (offset, _) in &trunk.entries {
+                listed.remove(&offset.0);
+                if free.contains(&offset.0) {
+                    kept_free.push(offset.clone());
+                }
+            }
+        }
+        let entries = self
+            .pinned_pages
+            .iter()
+            .map(|(_, offset)| offset)
+            .chain(self.unflushed_pages.iter().map(|(_, offset)| offset))
+            .filter_map(|offset| {
+                listed
+                    .remove(&offset.0)
+                    .map(|version| (offset.clone(), version))
+            })
+            .collect();
+        let free_pages = self
+            .free_pages
+            .iter()
+            .filter(|offset| listed.remove(&offset.0).is_some())
+            .cloned()
+            .collect();
+        (entries, free_pages, kept_free)
+    }
+
+    /// load_freelist recovers the root and the free pages persisted by the commit of the given record,
+    /// e.g. when reopening a tree. Pages following the ones in use as of that commit were written
+    /// by writes which were never committed, these are truncated unless the pager is read only.
+    pub fn load_freelist(&mut self, record: &Record) -> Result<(), Error> {
+        self.root = record.root.clone();
+        self.root_version = self.version;
+        if !self.read_only {
+            self.store.truncate(record.num_pages)?;
+        }
+        let mut next = match record.freelist {
+            Offset(0) => None,
+            ref offset => Some(offset.clone()),
+        };
+        while let Some(offset) = next {
+            let trunk = FreelistPage::try_from(self.get_page(&offset)?)?;
+            next = trunk.next.clone();
+            self.freelist.push((offset, trunk));
+        }
+        // Pages are reused from the head of the chain first.
+        for (_, trunk) in self.freelist.iter().rev() {
+            for (page, version) in &trunk.entries {
+                match version {
+                    0 => self.free_pages.push(page.clone()),
+                    // Versions might have been dropped from the log, e.g. once the tree was vacuumed.
+                    version => self
+                        .pinned_pages
+                        .push((cmp::min(*version, self.version), page.clone())),
+                }
+            }
+        }
+        // The recovered commit is the one found on disk, while pins do not survive reopening the tree.
+        self.durable_version = self.version;
+        self.reclaim()
     }
 
-    pub fn write_page_at_offset(&mut self, page: Page, offset: &Offset) -> Result<(), Error> {
-        self.file.seek(SeekFrom::Start(offset.0 as u64))?;
-        self.file.write_all(&page.get_data())?;
+    /// set_version sets the version of the last committed root along with the oldest version
+    /// whose pages were not reused, e.g. when reopening a tree.
+    pub fn set_version(&mut self, version: usize, oldest_version: usize) {
+        self.version = version;
+        self.oldest_version = oldest_version;
+    }
+
+    /// publish makes the last committed root, whose record was logged, the one read by readers.
+    pub fn publish(&mut self, root: Offset) {
+        self.root = root;
+        self.root_version = self.version;
+    }
+
+    /// root returns the last published root.
+    pub fn root(&self) -> Offset {
+        self.root.clone()
+    }
+
+    /// root_version returns the version of the last published root.
+    pub fn root_version(&self) -> usize {
+        self.root_version
+    }
+
+    /// oldest_version returns the oldest version whose pages were not reused.
+    pub fn oldest_version(&self) -> usize {
+        self.oldest_version
+    }
+
+    /// pin prevents the pages referenced by the root of the given version from being reused
+    /// until it is unpinned.
+    /// Pins are taken by readers sharing the pager, thus while a commit is flushed.
+    pub fn pin(&self, version: usize, root: Offset) -> Result<(), Error> {
+        self.pins.lock()?.entry(version).or_insert((root, 0)).1 += 1;
+        Ok(())
+    }
+
+    /// unpin releases a pin acquired by pin, the pages it kept are reclaimed by the writer once needed.
+    pub fn unpin(&self, version: usize) -> Result<(), Error> {
+        let mut pins = self.pins.lock()?;
+        if let Some((_, count)) = pins.get_mut(&version) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&version);
+                self.unpinned.store(true, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// set_durable records that every commit so far was flushed to disk along with its wal record,
+    /// the pages released by these commits may be reused.
+    pub fn set_durable(&mut self) -> Result<(), Error> {
+        self.durable_version = self.version;
+        self.reclaim()
+    }
+
+    /// reclaim frees the pages released by flushed commits after the oldest pinned root was committed,
+    /// a page released by the commit of some version is referenced by the roots of the preceding versions only.
+    /// The published root is pinned implicitly as readers may pin it at any time.
+    fn reclaim(&mut self) -> Result<(), Error> {
+        *self.unpinned.get_mut() = false;
+        let (durable_version, root_version) = (self.durable_version, self.root_version);
+        let oldest_pin = self
+            .pins
+            .get_mut()?
+            .keys()
+            .next()
+            .map_or(root_version, |pin| cmp::min(*pin, root_version));
+        let (free, pinned): (Vec<_>, Vec<_>) = self
+            .pinned_pages
+            .drain(..)
+            .partition(|(version, _)| *version <= durable_version && oldest_pin >= *version);
+        self.pinned_pages = pinned;
+        for (version, offset) in free {
+            // Once reused the pages are no longer those of the preceding versions.
+            self.oldest_version = cmp::max(self.oldest_version, version);
+            self.free_pages.push(offset);
+        }
+        let (free, unflushed): (Vec<_>, Vec<_>) = self
+            .unflushed_pages
+            .drain(..)
+            .partition(|(version, _)| *version <= durable_version);
+        self.unflushed_pages = unflushed;
+        self.free_pages
+            .extend(free.into_iter().map(|(_, offset)| offset));
         Ok(())
     }
+
+    /// pinned_roots returns the roots of the versions currently pinned.
+    pub fn pinned_roots(&self) -> Result<Vec<Offset>, Error> {
+        Ok(self
+            .pins
+            .lock()?
+            .values()
+            .map(|(root, _)| root.clone())
+            .collect())
+    }
+
+    /// collect frees every page following the superblock which is not one of the given reachable pages
+    /// nor a freelist trunk page, returning the number of pages which were freed. The roots of any version which is neither pinned
+    /// nor the last committed one might reference freed pages and are therefore no longer readable.
+    /// The freed pages are reused once the following commit, which records the oldest readable version, is flushed.
+    pub fn collect(&mut self, reachable: &HashSet<usize>) -> Result<usize, Error> {
+        let known: HashSet<usize> = self
+            .freelist
+            .iter()
+            .map(|(offset, _)| offset)
+            .chain(&self.free_pages)
+            .chain(self.unflushed_pages.iter().map(|(_, offset)| offset))
+            .map(|Offset(offset)| *offset)
+            .collect();
+        let version = self.version + 1;
+        // Lower offsets are reused first.
+        let freed: Vec<(usize, Offset)> = (1..self.store.num_pages())
+            .rev()
+            .map(|page| page * PAGE_SIZE)
+            .filter(|offset| !reachable.contains(offset) && !known.contains(offset))
+            .map(|offset| (version, Offset(offset)))
+            .collect();
+        let count = freed.len();
+        self.unflushed_pages.extend(freed);
+        self.pinned_pages
+            .retain(|(_, offset)| reachable.contains(&offset.0));
+        let last_version = self.version;
+        let oldest_live = self
+            .pins
+            .get_mut()?
+            .keys()
+            .next()
+            .map_or(last_version, |pin| cmp::min(*pin, last_version));
+        self.oldest_version = cmp::max(self.oldest_version, oldest_live);
+        Ok(count)
+    }
+
+    /// rollback reverts to the last commit, pages written since are unreferenced and can be reused
+    /// while pages released since are still referenced by the committed root.
+    pub fn rollback(&mut self) {
+        self.free_pages.extend(self.dirty_pages.drain().map(Offset));
+        self.released_pages.clear();
+    }
+
+    /// set_cache_capacity sets the number of pages kept in memory, zero disables the page cache.
+    pub fn set_cache_capacity(&mut self, capacity: usize) -> Result<(), Error> {
+        self.cache.set_capacity(capacity)
+    }
+
+    /// cache_capacity returns the number of pages kept in memory.
+    pub fn cache_capacity(&self) -> Result<usize, Error> {
+        Ok(self.cache.capacity())
+    }
+
+    /// cache_stats returns the number of page reads served by the page cache and the number of those read from disk.
+    pub fn cache_stats(&self) -> Result<CacheStats, Error> {
+        Ok(self.cache.stats())
+    }
+
+    /// sync flushes the pages written so far to disk, pages are read alongside.
+    pub fn sync(&self) -> Result<(), Error> {
+        self.store.sync()
+    }
+
+    pub fn write_page_at_offset(&mut self, mut page: Page, offset: &Offset) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        page.write_checksum();
+        self.store.write_page(offset, &page)?;
+        // The cache keeps holding what is found on disk.
+        self.cache.insert(offset.0, page)
+    }
 }
@@ -0,0 +1,342 @@
+use crate::btree::{child_index, read_node, read_overflow};
+use crate::error::Error;
+use crate::node_type::{KeyValuePair, NodeType, Offset};
+use crate::snapshot::Snapshot;
+use std::ops::{Bound, RangeBounds};
+
+/// Cursor is a position in the leaves of the tree along with the path of internal nodes leading to it.
+struct Cursor {
+    /// The children of every internal node on the root-to-leaf path and the index of the child taken.
+    path: Vec<(Vec<Offset>, usize)>,
+    /// The pairs of the current leaf.
+    pairs: Vec<KeyValuePair>,
+    /// For a front cursor the index of the next pair, for a back cursor one past the next pair.
+    position: usize,
+}
+
+/// Range is a double-ended iterator over the key-value pairs of a tree in key order,
+/// see BTree::range, BTree::iter and BTree::scan_prefix.
+/// The range holds a snapshot of the tree, thus it is not affected by writes made while iterating.
+pub struct Range {
+    snapshot: Snapshot,
+    start: Bound<String>,
+    end: Bound<String>,
+    front: Option<Cursor>,
+    back: Option<Cursor>,
+    /// The last keys yielded from either end, the iteration is over once the two ends meet.
+    front_last: Option<String>,
+    back_last: Option<String>,
+    finished: bool,
+}
+
+impl Range {
+    pub(crate) fn new<K, R>(snapshot: Snapshot, range: R) -> Range
+    where
+        K: AsRef<str>,
+        R: RangeBounds<K>,
+    {
+        let to_owned = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(key.as_ref().to_string()),
+            Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_string()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        Range {
+            snapshot,
+            start: to_owned(range.start_bound()),
+            end: to_owned(range.end_bound()),
+            front: None,
+            back: None,
+            front_last: None,
+            back_last: None,
+            finished: false,
+        }
+    }
+
+    /// seek descends once from the root to the leaf which holds the given bound,
+    /// positioning a front (or back) cursor on the first (or last) pair within it.
+    fn seek(&mut self, bound: &Bound<String>, forward: bool) -> Result<Cursor, Error> {
+        let mut path = vec![];
+        let mut offset = self.snapshot.root().clone();
+        loop {
+            let node = read_node(self.snapshot.pager(), &offset)?;
+            match node.node_type {
+                NodeType::Internal(children, keys) => {
+                    let idx = match bound {
+                        Bound::Included(key) | Bound::Excluded(key) => child_index(&keys, key),
+                        Bound::Unbounded if forward => 0,
+                        Bound::Unbounded => children.len().saturating_sub(1),
+                    };
+                    offset = children.get(idx).ok_or(Error::UnexpectedError)?.clone();
+                    path.push((children, idx));
+                }
+                NodeType::Leaf(pairs) => {
+                    let position = match (bound, forward) {
+                        (Bound::Included(key), true) | (Bound::Excluded(key), false) => {
+                            pairs.partition_point(|pair| pair.key < *key)
+                        }
+                        (Bound::Excluded(key), true) | (Bound::Included(key), false) => {
+                            pairs.partition_point(|pair| pair.key <= *key)
+                        }
+                        (Bound::Unbounded, true) => 0,
+                        (Bound::Unbounded, false) => pairs.len(),
+                    };
+                    return Ok(Cursor {
+                        path,
+                        pairs,
+                        position,
+                    });
+                }
+                NodeType::Unexpected => return Err(Error::UnexpectedError),
+            }
+        }
+    }
+
+    /// step moves a cursor to the next (or previous) leaf,
+    /// returning false once there are no more leaves in that direction.
+    fn step(&mut self, cursor: &mut Cursor, forward: bool) -> Result<bool, Error> {
+        // Climb up until reaching a node with a sibling of the current sub tree.
+        let mut offset = loop {
+            match cursor.path.last_mut() {
+                Some((children, idx)) if forward && *idx + 1 < children.len() => {
+                    *idx += 1;
+                    break children[*idx].clone();
+                }
+                Some((children, idx)) if !forward && *idx > 0 => {
+                    *idx -= 1;
+                    break children[*idx].clone();
+                }
+                Some(_) => {
+                    cursor.path.pop();
+                }
+                None => return Ok(false),
+            }
+        };
+        // Descend to the leftmost (or rightmost) leaf of that sibling.
+        loop {
+            let node = read_node(self.snapshot.pager(), &offset)?;
+            match node.node_type {
+                NodeType::Internal(children, _) => {
+                    let idx = match forward {
+                        true => 0,
+                        false => children.len().saturating_sub(1),
+                    };
+                    offset = children.get(idx).ok_or(Error::UnexpectedError)?.clone();
+                    cursor.path.push((children, idx));
+                }
+                NodeType::Leaf(pairs) => {
+                    cursor.position = match forward {
+                        true => 0,
+                        false => pairs.len(),
+                    };
+                    cursor.pairs = pairs;
+                    return Ok(true);
+                }
+                NodeType::Unexpected => return Err(Error::UnexpectedError),
+            }
+        }
+    }
+
+    /// front_pair yields the next pair from the front, if it is within the range and was not yielded from the back.
+    fn front_pair(&mut self) -> Result<Option<KeyValuePair>, Error> {
+        let mut cursor = match self.front.take() {
+            Some(cursor) => cursor,
+            None => self.seek(&self.start.clone(), true)?,
+        };
+        // Leaves may be empty, e.g. a root leaf following deletions.
+        while cursor.position >= cursor.pairs.len() {
+            if !self.step(&mut cursor, true)? {
+                return Ok(None);
+            }
+        }
+        let pair = cursor.pairs[cursor.position].clone();
+        cursor.position += 1;
+        self.front = Some(cursor);
+
+        let past_end = match &self.end {
+            Bound::Included(end) => pair.key > *end,
+            Bound::Excluded(end) => pair.key >= *end,
+            Bound::Unbounded => false,
+        };
+        let met_back = matches!(&self.back_last, Some(last) if pair.key >= *last);
+        if past_end || met_back {
+            return Ok(None);
+        }
+        self.front_last = Some(pair.key.clone());
+        read_overflow(self.snapshot.pager(), pair).map(Some)
+    }
+
+    /// back_pair yields the next pair from the back, if it is within the range and was not yielded from the front.
+    fn back_pair(&mut self) -> Result<Option<KeyValuePair>, Error> {
+        let mut cursor = match self.back.take() {
+            Some(cursor) => cursor,
+            None => self.seek(&self.end.clone(), false)?,
+        };
+        while cursor.position == 0 {
+            if !self.step(&mut cursor, false)? {
+                return Ok(None);
+            }
+        }
+        cursor.position -= 1;
+        let pair = cursor.pairs[cursor.position].clone();
+        self.back = Some(cursor);
+
+        let before_start = match &self.start {
+            Bound::Included(start) => pair.key < *start,
+            Bound::Excluded(start) => pair.key <= *start,
+            Bound::Unbounded => false,
+        };
+        let met_front = matches!(&self.front_last, Some(last) if pair.key <= *last);
+        if before_start || met_front {
+            return Ok(None);
+        }
+        self.back_last = Some(pair.key.clone());
+        read_overflow(self.snapshot.pager(), pair).map(Some)
+    }
+
+    /// finish ends the iteration on the first error or once either end is exhausted.
+    fn finish(
+        &mut self,
+        res: Result<Option<KeyValuePair>, Error>,
+    ) -> Option<Result<KeyValuePair, Error>> {
+        match res {
+            Ok(Some(pair)) => Some(Ok(pair)),
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Err(e) => {
+                self.finished = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Iterator for Range {
+    type Item = Result<KeyValuePair, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let res = self.front_pair();
+        self.finish(res)
+    }
+}
+
+impl DoubleEndedIterator for Range {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let res = self.back_pair();
+        self.finish(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn iter_works_in_both_directions() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_iter")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_iter/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in (0..100).rev() {
+            btree.insert(KeyValuePair::new(format!("{:03}", i), i.to_string()))?;
+        }
+
+        let pairs = btree.iter()?.collect::<Result<Vec<_>, Error>>()?;
+        let expected: Vec<KeyValuePair> = (0..100)
+            .map(|i| KeyValuePair::new(format!("{:03}", i), i.to_string()))
+            .collect();
+        assert_eq!(pairs, expected);
+
+        let keys = btree
+            .iter()?
+            .rev()
+            .map(|pair| pair.map(|pair| pair.key))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let expected: Vec<String> = (0..100).rev().map(|i| format!("{:03}", i)).collect();
+        assert_eq!(keys, expected);
+
+        // Both ends meet without yielding a pair twice.
+        let mut iter = btree.iter()?;
+        let mut keys = vec![];
+        while let Some(pair) = iter.next() {
+            keys.push(pair?.key);
+            if let Some(pair) = iter.next_back() {
+                keys.push(pair?.key);
+            }
+        }
+        keys.sort();
+        assert_eq!(
+            keys,
+            (0..100).map(|i| format!("{:03}", i)).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn range_respects_bounds() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::fs;
+        use std::ops::Bound;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_range")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_range/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..50 {
+            btree.insert(KeyValuePair::new(format!("{:03}", i * 2), i.to_string()))?;
+        }
+
+        let keys = |range: (Bound<&str>, Bound<&str>)| -> Result<Vec<String>, Error> {
+            btree
+                .range::<&str, _>(range)?
+                .map(|pair| pair.map(|pair| pair.key))
+                .collect()
+        };
+        assert_eq!(
+            keys((Bound::Included("010"), Bound::Excluded("016")))?,
+            vec!["010", "012", "014"]
+        );
+        assert_eq!(
+            keys((Bound::Excluded("010"), Bound::Included("016")))?,
+            vec!["012", "014", "016"]
+        );
+        assert_eq!(
+            keys((Bound::Included("009"), Bound::Excluded("013")))?,
+            vec!["010", "012"]
+        );
+        assert_eq!(
+            keys((Bound::Unbounded, Bound::Excluded("004")))?,
+            vec!["000", "002"]
+        );
+        assert_eq!(
+            keys((Bound::Excluded("094"), Bound::Unbounded))?,
+            vec!["096", "098"]
+        );
+        assert!(keys((Bound::Included("050"), Bound::Excluded("050")))?.is_empty());
+        assert!(keys((Bound::Included("060"), Bound::Included("040")))?.is_empty());
+
+        let keys = btree
+            .range("020".."026")?
+            .rev()
+            .map(|pair| pair.map(|pair| pair.key))
+            .collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(keys, vec!["024", "022", "020"]);
+        Ok(())
+    }
+}
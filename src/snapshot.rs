@@ -0,0 +1,194 @@
+use crate::btree::{prefix_range, search_tree};
+use crate::error::Error;
+use crate::node_type::{KeyValuePair, Offset};
+use crate::pager::Pager;
+use crate::range::Range;
+use std::ops::RangeBounds;
+use std::sync::{Arc, RwLock};
+
+/// Snapshot is a read-only view of a tree as of a committed root, see BTree::snapshot and BTree::at_version.
+/// The tree may be written while the snapshot is held, the pages referenced by the snapshot's root
+/// are not reused until it is dropped. Snapshots may be cloned and sent to other threads.
+pub struct Snapshot {
+    pager: Arc<RwLock<Pager>>,
+    root: Offset,
+    /// The version of the root pinned by the snapshot.
+    version: usize,
+}
+
+impl Snapshot {
+    /// latest pins the last root published by the pager.
+    pub(crate) fn latest(pager: Arc<RwLock<Pager>>) -> Result<Snapshot, Error> {
+        let (root, version) = {
+            // The root is pinned before a later commit could reuse its pages.
+            let guard = pager.read()?;
+            let (root, version) = (guard.root(), guard.root_version());
+            guard.pin(version, root.clone())?;
+            (root, version)
+        };
+        Ok(Snapshot {
+            pager,
+            root,
+            version,
+        })
+    }
+
+    /// at_version pins the root of the given version,
+    /// fails with VersionNotFound if the pages it references might have been reused.
+    pub(crate) fn at_version(
+        pager: Arc<RwLock<Pager>>,
+        root: Offset,
+        version: usize,
+    ) -> Result<Snapshot, Error> {
+        {
+            let guard = pager.read()?;
+            if version < guard.oldest_version() || version > guard.root_version() {
+                return Err(Error::VersionNotFound);
+            }
+            guard.pin(version, root.clone())?;
+        }
+        Ok(Snapshot {
+            pager,
+            root,
+            version,
+        })
+    }
+
+    /// version returns the version of the root the snapshot was taken at.
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    /// root returns the root the snapshot was taken at.
+    pub(crate) fn root(&self) -> &Offset {
+        &self.root
+    }
+
+    /// pager returns the pager the snapshot reads from.
+    pub(crate) fn pager(&self) -> &RwLock<Pager> {
+        &self.pager
+    }
+
+    /// search searches for a specific key as of the snapshot.
+    pub fn search(&self, key: String) -> Result<KeyValuePair, Error> {
+        search_tree(&self.pager, &self.root, &key)
+    }
+
+    /// range returns a double-ended iterator over the key-value pairs of the snapshot within the given bounds.
+    pub fn range<K, R>(&self, range: R) -> Range
+    where
+        K: AsRef<str>,
+        R: RangeBounds<K>,
+    {
+        Range::new(self.clone(), range)
+    }
+
+    /// scan_prefix returns a double-ended iterator over the key-value pairs of the snapshot
+    /// whose key starts with the given prefix.
+    pub fn scan_prefix(&self, prefix: &str) -> Range {
+        Range::new(self.clone(), prefix_range(prefix))
+    }
+
+    /// iter returns a double-ended iterator over all key-value pairs of the snapshot.
+    pub fn iter(&self) -> Range {
+        self.range::<String, _>(..)
+    }
+}
+
+impl Clone for Snapshot {
+    fn clone(&self) -> Snapshot {
+        // The root is pinned already, pinning it once more keeps it pinned until both are dropped.
+        if let Ok(pager) = self.pager.read() {
+            let _ = pager.pin(self.version, self.root.clone());
+        }
+        Snapshot {
+            pager: Arc::clone(&self.pager),
+            root: self.root.clone(),
+            version: self.version,
+        }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Ok(pager) = self.pager.read() {
+            let _ = pager.unpin(self.version);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn snapshot_reads_are_consistent() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::page_layout::PAGE_SIZE;
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_snapshot")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_snapshot/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(
+                format!("{:02}", i),
+                "x".repeat(PAGE_SIZE),
+            ))?;
+        }
+
+        let snapshot = btree.snapshot()?;
+        // Rewriting the values releases their overflow pages, these must not be reused
+        // while the snapshot is held.
+        for i in 0..20 {
+            btree.upsert(KeyValuePair::new(
+                format!("{:02}", i),
+                "y".repeat(PAGE_SIZE),
+            ))?;
+        }
+        for i in 0..10 {
+            btree.delete(Key(format!("{:02}", i)))?;
+        }
+        for i in 20..30 {
+            btree.insert(KeyValuePair::new(
+                format!("{:02}", i),
+                "z".repeat(PAGE_SIZE),
+            ))?;
+        }
+
+        assert_eq!(
+            snapshot.search("05".to_string())?.value,
+            "x".repeat(PAGE_SIZE)
+        );
+        assert!(matches!(
+            snapshot.search("25".to_string()),
+            Err(Error::KeyNotFound)
+        ));
+        let pairs = snapshot.iter().collect::<Result<Vec<_>, Error>>()?;
+        let expected: Vec<KeyValuePair> = (0..20)
+            .map(|i| KeyValuePair::new(format!("{:02}", i), "x".repeat(PAGE_SIZE)))
+            .collect();
+        assert_eq!(pairs, expected);
+        assert_eq!(snapshot.range("08".."12").count(), 4);
+        assert_eq!(snapshot.scan_prefix("1").count(), 10);
+
+        assert_eq!(btree.search("15".to_string())?.value, "y".repeat(PAGE_SIZE));
+        assert_eq!(btree.iter()?.count(), 20);
+
+        // Once the snapshot is dropped its pages are reused.
+        drop(snapshot);
+        let file_len = fs::metadata("/tmp/btree_snapshot/db")?.len();
+        for i in 20..25 {
+            btree.upsert(KeyValuePair::new(
+                format!("{:02}", i),
+                "w".repeat(PAGE_SIZE),
+            ))?;
+        }
+        assert_eq!(fs::metadata("/tmp/btree_snapshot/db")?.len(), file_len);
+        Ok(())
+    }
+}
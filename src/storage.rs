@@ -0,0 +1,527 @@
+use crate::error::Error;
+use crate::node_type::Offset;
+use crate::page::Page;
+pub use crate::page_layout::PAGE_SIZE;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Access determines whether a storage is opened for writing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Open an existing storage for reading only.
+    ReadOnly,
+    /// Open a storage for reading and writing, creating an empty one if there is none.
+    ReadWrite,
+}
+
+/// PageStore is a store of fixed size pages holding a tree file, e.g. a file or a memory map of one.
+/// Pages are addressed by their offset, the page numbered i is found at offset i * PAGE_SIZE
+/// and the first one holds the superblock. The store decides how it grows as pages are allocated.
+/// Reads take a shared reference as several threads may read at once.
+pub trait PageStore: Send + Sync {
+    /// read_page reads the page at the given offset, fails if it was never allocated.
+    /// Reading a page allocated but never written may fail as well, or return no valid page, e.g. zeros.
+    fn read_page(&self, offset: &Offset) -> Result<Page, Error>;
+
+    /// write_page writes the page at the given offset, fails unless the page was allocated.
+    fn write_page(&mut self, offset: &Offset, page: &Page) -> Result<(), Error>;
+
+    /// allocate_page allocates a page following the last page of the store, returning its offset.
+    /// The page is only readable once written.
+    fn allocate_page(&mut self) -> Result<Offset, Error>;
+
+    /// num_pages returns the number of pages allocated so far.
+    fn num_pages(&self) -> usize;
+
+    /// truncate drops the pages following the given number of pages, they are allocated again from there.
+    fn truncate(&mut self, num_pages: usize) -> Result<(), Error>;
+
+    /// sync flushes the pages written so far to durable storage. Pages may be read while syncing.
+    fn sync(&self) -> Result<(), Error>;
+
+    /// try_lock takes an advisory lock on the store which is held until it is dropped, shared or exclusive.
+    /// Returns false if the store is locked by others such that they cannot share it.
+    fn try_lock(&self, shared: bool) -> Result<bool, Error>;
+}
+
+/// Storage is a store of bytes holding a wal, e.g. a file. Page stores may be built upon it, see PagedStorage.
+/// Reads take a shared reference as several threads may read at once.
+pub trait Storage: Send + Sync {
+    /// read_at reads exactly enough bytes to fill buf starting at the given offset,
+    /// fails if the storage ends before.
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> Result<(), Error>;
+
+    /// write_at writes the given bytes at the given offset, extending the storage as needed.
+    fn write_at(&mut self, buf: &[u8], offset: usize) -> Result<(), Error>;
+
+    /// size returns the size of the storage in bytes.
+    fn size(&self) -> Result<usize, Error>;
+
+    /// set_size truncates the storage, or extends it with zeros, to the given size in bytes.
+    fn set_size(&mut self, size: usize) -> Result<(), Error>;
+
+    /// sync flushes the writes made so far to durable storage. The storage may be read while syncing.
+    fn sync(&self) -> Result<(), Error>;
+
+    /// try_lock takes an advisory lock on the storage which is held until it is dropped, shared or exclusive.
+    /// Returns false if the storage is locked by others such that they cannot share it.
+    fn try_lock(&self, shared: bool) -> Result<bool, Error>;
+}
+
+/// Backend opens the stores found at a path, the way a file system opens files.
+/// Trees are stored in files by default, see BTreeBuilder::backend.
+pub trait Backend: Send + Sync {
+    /// open_pages opens the page store holding a tree file at the given path.
+    fn open_pages(&self, path: &Path, access: Access) -> Result<Box<dyn PageStore>, Error>;
+
+    /// open_log opens the storage holding a wal at the given path.
+    fn open_log(&self, path: &Path, access: Access) -> Result<Box<dyn Storage>, Error>;
+
+    /// exists returns true if there is a storage at the given path.
+    fn exists(&self, path: &Path) -> bool;
+
+    /// rename moves the storage at from to the given path, replacing the storage found there.
+    /// The rename is durable once it returns, storage opened before keeps reading the storage it was opened on.
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error>;
+}
+
+/// FileBackend stores trees in files, locked while they are open so that processes do not write them at once.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileBackend;
+
+impl Backend for FileBackend {
+    fn open_pages(&self, path: &Path, access: Access) -> Result<Box<dyn PageStore>, Error> {
+        Ok(Box::new(PagedStorage::new(FileStorage::open(
+            path, access,
+        )?)?))
+    }
+
+    fn open_log(&self, path: &Path, access: Access) -> Result<Box<dyn Storage>, Error> {
+        Ok(Box::new(FileStorage::open(path, access)?))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        fs::rename(from, to)?;
+        sync_dir(to)
+    }
+}
+
+/// sync_dir flushes the directory holding the given path to disk, making a rename into it durable.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<(), Error> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Directories cannot be opened for syncing on other platforms, e.g. Windows.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+/// FileStorage is a file read and written at the given offsets without moving a shared cursor.
+pub struct FileStorage {
+    file: File,
+}
+
+impl FileStorage {
+    pub fn open(path: &Path, access: Access) -> Result<FileStorage, Error> {
+        let file = match access {
+            Access::ReadOnly => OpenOptions::new().read(true).open(path)?,
+            Access::ReadWrite => OpenOptions::new()
+                .create(true)
+                .read(true)
+                .write(true)
+                .truncate(false)
+                .open(path)?,
+        };
+        Ok(FileStorage { file })
+    }
+
+    /// file returns the underlying file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Storage for FileStorage {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> Result<(), Error> {
+        read_exact_at(&self.file, buf, offset as u64)?;
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: usize) -> Result<(), Error> {
+        write_all_at(&self.file, buf, offset as u64)?;
+        Ok(())
+    }
+
+    fn size(&self) -> Result<usize, Error> {
+        Ok(self.file.metadata()?.len() as usize)
+    }
+
+    fn set_size(&mut self, size: usize) -> Result<(), Error> {
+        self.file.set_len(size as u64)?;
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn try_lock(&self, shared: bool) -> Result<bool, Error> {
+        let res = match shared {
+            true => self.file.try_lock_shared(),
+            false => self.file.try_lock(),
+        };
+        match res {
+            Ok(()) => Ok(true),
+            Err(TryLockError::WouldBlock) => Ok(false),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
+
+/// PagedStorage is a page store built upon a storage of bytes, holding each page at its offset.
+/// Pages are allocated at the end of the storage, which grows as they are written.
+pub struct PagedStorage<S> {
+    storage: S,
+    num_pages: usize,
+}
+
+impl<S: Storage> PagedStorage<S> {
+    pub fn new(storage: S) -> Result<PagedStorage<S>, Error> {
+        // Round up so a partially written trailing page is never overwritten.
+        let num_pages = storage.size()?.div_ceil(PAGE_SIZE);
+        Ok(PagedStorage { storage, num_pages })
+    }
+
+    /// storage returns the underlying storage.
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+}
+
+impl<S: Storage> PageStore for PagedStorage<S> {
+    fn read_page(&self, offset: &Offset) -> Result<Page, Error> {
+        let mut data = [0x00; PAGE_SIZE];
+        self.storage.read_at(&mut data, offset.0)?;
+        Ok(Page::new(data))
+    }
+
+    fn write_page(&mut self, offset: &Offset, page: &Page) -> Result<(), Error> {
+        if !offset.0.is_multiple_of(PAGE_SIZE) || offset.0 >= self.num_pages * PAGE_SIZE {
+            return Err(Error::UnexpectedError);
+        }
+        self.storage.write_at(page.get_data(), offset.0)
+    }
+
+    fn allocate_page(&mut self) -> Result<Offset, Error> {
+        let offset = Offset(self.num_pages * PAGE_SIZE);
+        self.num_pages += 1;
+        Ok(offset)
+    }
+
+    fn num_pages(&self) -> usize {
+        self.num_pages
+    }
+
+    fn truncate(&mut self, num_pages: usize) -> Result<(), Error> {
+        self.storage.set_size(num_pages * PAGE_SIZE)?;
+        self.num_pages = num_pages;
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.storage.sync()
+    }
+
+    fn try_lock(&self, shared: bool) -> Result<bool, Error> {
+        self.storage.try_lock(shared)
+    }
+}
+
+/// read_exact_at fills buf with the bytes of the file at the given offset, without moving a shared cursor.
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    file.read_exact_at(buf, offset)
+}
+
+/// write_all_at writes buf to the file at the given offset, without moving a shared cursor.
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    file.write_all_at(buf, offset)
+}
+
+/// Windows reads at an offset with seek_read, which moves the cursor and may read fewer bytes than asked for.
+/// The cursor is never relied upon, reads and writes always name their offset.
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Windows writes at an offset with seek_write, which moves the cursor and may write fewer bytes than asked for.
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// MemoryFile is the content of a storage kept in memory, shared by every storage opened at its path,
+/// along with the locks taken on it.
+#[derive(Debug, Default)]
+struct MemoryFile {
+    data: RwLock<Vec<u8>>,
+    locks: Mutex<Locks>,
+}
+
+/// Locks counts the locks held on a memory file, either shared ones or a single exclusive one.
+#[derive(Debug, Default)]
+struct Locks {
+    shared: usize,
+    exclusive: bool,
+}
+
+impl Locks {
+    /// acquire takes a lock if it can be shared with the locks held, returning whether it was taken.
+    fn acquire(&mut self, shared: bool) -> bool {
+        match shared {
+            true if !self.exclusive => self.shared += 1,
+            false if !self.exclusive && self.shared == 0 => self.exclusive = true,
+            _ => return false,
+        }
+        true
+    }
+
+    /// release drops a lock taken by acquire.
+    fn release(&mut self, shared: bool) {
+        match shared {
+            true => self.shared -= 1,
+            false => self.exclusive = false,
+        }
+    }
+}
+
+/// MemoryBackend keeps trees in memory, e.g. for tests and caches, they are gone once the backend is dropped.
+/// Clones of a backend share their storage, thus a tree may be reopened using a clone of the backend it was built with.
+/// Trees are locked while they are open just like files, so that two trees of a process do not write the same storage.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBackend {
+    storage: Arc<Mutex<HashMap<PathBuf, Arc<MemoryFile>>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+
+    /// open_storage opens the storage at the given path, fails with TreeNotFound if there is none to read.
+    fn open_storage(&self, path: &Path, access: Access) -> Result<MemoryStorage, Error> {
+        let mut storage = self.storage.lock()?;
+        let file = match (storage.get(path), access) {
+            (Some(file), _) => Arc::clone(file),
+            (None, Access::ReadOnly) => return Err(Error::TreeNotFound(path.to_path_buf())),
+            (None, Access::ReadWrite) => {
+                let file = Arc::new(MemoryFile::default());
+                storage.insert(path.to_path_buf(), Arc::clone(&file));
+                file
+            }
+        };
+        Ok(MemoryStorage {
+            file,
+            lock: Mutex::new(None),
+        })
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn open_pages(&self, path: &Path, access: Access) -> Result<Box<dyn PageStore>, Error> {
+        Ok(Box::new(PagedStorage::new(
+            self.open_storage(path, access)?,
+        )?))
+    }
+
+    fn open_log(&self, path: &Path, access: Access) -> Result<Box<dyn Storage>, Error> {
+        Ok(Box::new(self.open_storage(path, access)?))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.storage
+            .lock()
+            .is_ok_and(|storage| storage.contains_key(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        let mut storage = self.storage.lock()?;
+        let file = storage
+            .remove(from)
+            .ok_or_else(|| Error::TreeNotFound(from.to_path_buf()))?;
+        storage.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+}
+
+/// MemoryStorage is a storage of a MemoryBackend.
+struct MemoryStorage {
+    file: Arc<MemoryFile>,
+    /// The lock held by this storage on its file, whether it is shared.
+    lock: Mutex<Option<bool>>,
+}
+
+impl Storage for MemoryStorage {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> Result<(), Error> {
+        let data = self.file.data.read()?;
+        let bytes = offset
+            .checked_add(buf.len())
+            .and_then(|end| data.get(offset..end))
+            .ok_or(Error::UnexpectedError)?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: usize) -> Result<(), Error> {
+        let mut data = self.file.data.write()?;
+        let end = offset + buf.len();
+        if data.len() < end {
+            data.resize(end, 0x00);
+        }
+        data[offset..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn size(&self) -> Result<usize, Error> {
+        Ok(self.file.data.read()?.len())
+    }
+
+    fn set_size(&mut self, size: usize) -> Result<(), Error> {
+        self.file.data.write()?.resize(size, 0x00);
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn try_lock(&self, shared: bool) -> Result<bool, Error> {
+        let mut lock = self.lock.lock()?;
+        let mut locks = self.file.locks.lock()?;
+        // A lock held already is released before taking the new one, the way files convert their locks.
+        if let Some(held) = lock.take() {
+            locks.release(held);
+        }
+        if !locks.acquire(shared) {
+            return Ok(false);
+        }
+        *lock = Some(shared);
+        Ok(true)
+    }
+}
+
+impl Drop for MemoryStorage {
+    fn drop(&mut self) {
+        if let (Ok(Some(held)), Ok(mut locks)) = (self.lock.get_mut(), self.file.locks.lock()) {
+            locks.release(*held);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn memory_storage_works() -> Result<(), Error> {
+        use crate::storage::{Access, Backend, MemoryBackend};
+        use std::path::Path;
+
+        let backend = MemoryBackend::new();
+        assert!(matches!(
+            backend.open_log(Path::new("a"), Access::ReadOnly),
+            Err(Error::TreeNotFound(path)) if path == Path::new("a")
+        ));
+        let mut storage = backend.open_log(Path::new("a"), Access::ReadWrite)?;
+        storage.write_at(b"shalom", 4)?;
+        assert_eq!(storage.size()?, 10);
+        let mut buf = [0x00; 6];
+        storage.read_at(&mut buf, 4)?;
+        assert_eq!(&buf, b"shalom");
+        assert!(storage.read_at(&mut buf, 5).is_err());
+
+        // Storage opened at the same path shares the same bytes, also once renamed.
+        backend.rename(Path::new("a"), Path::new("b"))?;
+        assert!(!backend.exists(Path::new("a")));
+        let mut renamed = backend.open_log(Path::new("b"), Access::ReadOnly)?;
+        renamed.read_at(&mut buf, 4)?;
+        assert_eq!(&buf, b"shalom");
+        renamed.set_size(4)?;
+        assert_eq!(storage.size()?, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn paged_storage_allocates_pages() -> Result<(), Error> {
+        use crate::node_type::Offset;
+        use crate::page::Page;
+        use crate::storage::{Access, Backend, MemoryBackend, PAGE_SIZE};
+        use std::path::Path;
+
+        let backend = MemoryBackend::new();
+        let mut pages = backend.open_pages(Path::new("a"), Access::ReadWrite)?;
+        assert_eq!(pages.num_pages(), 0);
+        // Pages are only written once allocated.
+        let page = Page::new([0xAB; PAGE_SIZE]);
+        assert!(pages.write_page(&Offset(0), &page).is_err());
+        assert_eq!(pages.allocate_page()?, Offset(0));
+        assert_eq!(pages.allocate_page()?, Offset(PAGE_SIZE));
+        pages.write_page(&Offset(PAGE_SIZE), &page)?;
+        assert_eq!(pages.num_pages(), 2);
+        assert_eq!(
+            pages.read_page(&Offset(PAGE_SIZE))?.get_data(),
+            page.get_data()
+        );
+
+        // Reopened stores allocate after the pages found, truncated ones after the pages kept.
+        let mut reopened = backend.open_pages(Path::new("a"), Access::ReadWrite)?;
+        assert_eq!(reopened.num_pages(), 2);
+        reopened.truncate(1)?;
+        assert!(reopened.read_page(&Offset(PAGE_SIZE)).is_err());
+        assert_eq!(reopened.allocate_page()?, Offset(PAGE_SIZE));
+        Ok(())
+    }
+}
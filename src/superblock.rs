@@ -0,0 +1,184 @@
+use crate::error::Error;
+use crate::node_type::Offset;
+use crate::page::Page;
+use crate::page_layout::{
+    B_PARAMETER_OFFSET, FORMAT_VERSION, FORMAT_VERSION_OFFSET, FREELIST_OFFSET, ID_OFFSET, ID_SIZE,
+    MAGIC, MAGIC_OFFSET, MAGIC_SIZE, MAX_CELL_SIZE, MAX_CELL_SIZE_OFFSET, MAX_KEY_SIZE,
+    MAX_KEY_SIZE_OFFSET, NUM_PAGES_OFFSET, OLDEST_VERSION_OFFSET, PAGE_SIZE, PAGE_SIZE_OFFSET,
+    ROOT_OFFSET, VERSION_OFFSET,
+};
+use crate::wal::Record;
+use std::convert::TryFrom;
+use uuid::Uuid;
+
+/// Superblock is the header of a tree file, persisted in its first page.
+/// It records the layout the file was written with so a file can be told apart
+/// from garbage and is never opened with incompatible parameters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Superblock {
+    pub page_size: usize,
+    pub b: usize,
+    pub max_key_size: usize,
+    pub max_cell_size: usize,
+    /// The last committed root.
+    pub root: Offset,
+    /// The oldest version of the tree which is still readable,
+    /// the pages of older versions might have been reused.
+    pub oldest_version: usize,
+    /// The first freelist trunk page as of the last commit, Offset(0) if there are no free pages.
+    pub freelist: Offset,
+    /// The number of pages in use by the tree file as of the last commit.
+    pub num_pages: usize,
+    /// The version of the last committed root.
+    pub version: usize,
+    /// The id of the tree file, recorded in the header of its wal as well.
+    pub id: Uuid,
+}
+
+impl Superblock {
+    /// new returns a superblock describing the current page layout, for a new tree file.
+    pub fn new(b: usize, root: Offset) -> Superblock {
+        Superblock {
+            page_size: PAGE_SIZE,
+            b,
+            max_key_size: MAX_KEY_SIZE,
+            max_cell_size: MAX_CELL_SIZE,
+            root,
+            oldest_version: 1,
+            freelist: Offset(0),
+            num_pages: 0,
+            version: 0,
+            id: Uuid::new_v4(),
+        }
+    }
+
+    /// record returns the last commit recorded in the superblock.
+    pub fn record(&self) -> Record {
+        Record {
+            version: self.version,
+            root: self.root.clone(),
+            freelist: self.freelist.clone(),
+            num_pages: self.num_pages,
+            oldest_version: self.oldest_version,
+        }
+    }
+
+    /// set_record records a commit in the superblock.
+    pub fn set_record(&mut self, record: Record) {
+        self.root = record.root;
+        self.freelist = record.freelist;
+        self.num_pages = record.num_pages;
+        self.oldest_version = record.oldest_version;
+        self.version = record.version;
+    }
+
+    /// validate verifies the file was written with the same layout constants
+    /// and b parameter the tree is opened with.
+    pub fn validate(&self, b: usize) -> Result<(), Error> {
+        if self.page_size != PAGE_SIZE {
+            return Err(Error::InvalidHeader("page size does not match PAGE_SIZE"));
+        }
+        if self.max_key_size != MAX_KEY_SIZE {
+            return Err(Error::InvalidHeader("key size does not match MAX_KEY_SIZE"));
+        }
+        if self.max_cell_size != MAX_CELL_SIZE {
+            return Err(Error::InvalidHeader(
+                "cell size does not match MAX_CELL_SIZE",
+            ));
+        }
+        if self.b != b {
+            return Err(Error::InvalidHeader(
+                "b parameter does not match the tree file",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Implement TryFrom<Page> for Superblock allowing for easier
+/// deserialization of the file header.
+impl TryFrom<Page> for Superblock {
+    type Error = Error;
+    fn try_from(page: Page) -> Result<Superblock, Error> {
+        if page.get_ptr_from_offset(MAGIC_OFFSET, MAGIC_SIZE) != MAGIC {
+            return Err(Error::InvalidHeader("not a btree file"));
+        }
+        if page.get_value_from_offset(FORMAT_VERSION_OFFSET)? != FORMAT_VERSION {
+            return Err(Error::InvalidHeader("unsupported format version"));
+        }
+        Ok(Superblock {
+            page_size: page.get_value_from_offset(PAGE_SIZE_OFFSET)?,
+            b: page.get_value_from_offset(B_PARAMETER_OFFSET)?,
+            max_key_size: page.get_value_from_offset(MAX_KEY_SIZE_OFFSET)?,
+            max_cell_size: page.get_value_from_offset(MAX_CELL_SIZE_OFFSET)?,
+            root: Offset(page.get_value_from_offset(ROOT_OFFSET)?),
+            oldest_version: page.get_value_from_offset(OLDEST_VERSION_OFFSET)?,
+            freelist: Offset(page.get_value_from_offset(FREELIST_OFFSET)?),
+            num_pages: page.get_value_from_offset(NUM_PAGES_OFFSET)?,
+            version: page.get_value_from_offset(VERSION_OFFSET)?,
+            id: Uuid::from_slice(page.get_ptr_from_offset(ID_OFFSET, ID_SIZE))
+                .map_err(|_| Error::InvalidHeader("invalid tree file id"))?,
+        })
+    }
+}
+
+/// Implement TryFrom<&Superblock> for Page allowing for easier
+/// serialization of the file header.
+impl TryFrom<&Superblock> for Page {
+    type Error = Error;
+    fn try_from(superblock: &Superblock) -> Result<Page, Error> {
+        let mut page = Page::new([0x00; PAGE_SIZE]);
+        page.write_bytes_at_offset(&MAGIC, MAGIC_OFFSET, MAGIC_SIZE)?;
+        page.write_value_at_offset(FORMAT_VERSION_OFFSET, FORMAT_VERSION)?;
+        page.write_value_at_offset(PAGE_SIZE_OFFSET, superblock.page_size)?;
+        page.write_value_at_offset(B_PARAMETER_OFFSET, superblock.b)?;
+        page.write_value_at_offset(MAX_KEY_SIZE_OFFSET, superblock.max_key_size)?;
+        page.write_value_at_offset(MAX_CELL_SIZE_OFFSET, superblock.max_cell_size)?;
+        page.write_value_at_offset(ROOT_OFFSET, superblock.root.0)?;
+        page.write_value_at_offset(OLDEST_VERSION_OFFSET, superblock.oldest_version)?;
+        page.write_value_at_offset(FREELIST_OFFSET, superblock.freelist.0)?;
+        page.write_value_at_offset(NUM_PAGES_OFFSET, superblock.num_pages)?;
+        page.write_value_at_offset(VERSION_OFFSET, superblock.version)?;
+        page.write_bytes_at_offset(superblock.id.as_bytes(), ID_OFFSET, ID_SIZE)?;
+        Ok(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn superblock_to_page_works() -> Result<(), Error> {
+        use crate::node_type::Offset;
+        use crate::page::Page;
+        use crate::page_layout::PAGE_SIZE;
+        use crate::superblock::Superblock;
+        use std::convert::TryFrom;
+
+        let mut superblock = Superblock::new(2, Offset(PAGE_SIZE * 3));
+        superblock.freelist = Offset(PAGE_SIZE * 5);
+        superblock.num_pages = 8;
+        superblock.version = 13;
+        // Serialize data.
+        let page = Page::try_from(&superblock)?;
+        // Deserialize back the page.
+        let res = Superblock::try_from(page)?;
+
+        assert_eq!(res, superblock);
+        res.validate(2)?;
+        assert!(matches!(res.validate(3), Err(Error::InvalidHeader(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn garbage_is_not_a_superblock() {
+        use crate::page::Page;
+        use crate::page_layout::PAGE_SIZE;
+        use crate::superblock::Superblock;
+        use std::convert::TryFrom;
+
+        let res = Superblock::try_from(Page::new([0xAB; PAGE_SIZE]));
+        assert!(matches!(res, Err(Error::InvalidHeader("not a btree file"))));
+    }
+}
@@ -0,0 +1,319 @@
+use crate::btree::{WriteMode, Writer};
+use crate::error::Error;
+use crate::node_type::{Key, KeyValuePair, Offset};
+use std::sync::MutexGuard;
+
+/// Transaction groups several writes to a tree, see BTree::begin.
+/// The writes share a single copy-on-write root which is published atomically by commit,
+/// they are discarded by rollback or once the transaction is dropped without being committed.
+/// The transaction holds the writer of the tree, other writes wait until it is committed or rolled back.
+/// A write refused up front, e.g. with KeyAlreadyExists, leaves the transaction as it was while a write
+/// failing partway (e.g. on an I/O error) aborts it, later calls fail with TransactionAborted.
+pub struct Transaction<'a> {
+    writer: MutexGuard<'a, Writer>,
+    /// The root of the tree including the writes of the transaction so far.
+    root: Offset,
+    committed: bool,
+    /// Whether a write failed partway, its root might reference partially written pages.
+    aborted: bool,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(writer: MutexGuard<'a, Writer>, root: Offset) -> Transaction<'a> {
+        Transaction {
+            writer,
+            root,
+            committed: false,
+            aborted: false,
+        }
+    }
+
+    /// insert a key value pair, fails with KeyAlreadyExists if the key is already in the tree.
+    pub fn insert(&mut self, kv: KeyValuePair) -> Result<(), Error> {
+        self.write(kv, WriteMode::Insert)?;
+        Ok(())
+    }
+
+    /// upsert inserts a key value pair or replaces the value of an existing key,
+    /// returning the previous value if there was one.
+    pub fn upsert(&mut self, kv: KeyValuePair) -> Result<Option<String>, Error> {
+        self.write(kv, WriteMode::Upsert)
+    }
+
+    /// update replaces the value of an existing key returning the previous value,
+    /// fails with KeyNotFound if the key is not in the tree.
+    pub fn update(&mut self, kv: KeyValuePair) -> Result<String, Error> {
+        self.write(kv, WriteMode::Update)?
+            .ok_or(Error::UnexpectedError)
+    }
+
+    fn write(&mut self, kv: KeyValuePair, mode: WriteMode) -> Result<Option<String>, Error> {
+        self.check_aborted()?;
+        self.writer.check_write(&self.root, &kv, mode)?;
+        let res = self.writer.write_at(&self.root, kv);
+        let (root, previous) = self.abort_on_error(res)?;
+        self.root = root;
+        Ok(previous)
+    }
+
+    /// delete deletes a given key, fails with KeyNotFound if the key is not in the tree.
+    pub fn delete(&mut self, key: Key) -> Result<(), Error> {
+        self.check_aborted()?;
+        self.writer.check_delete(&self.root, &key)?;
+        let res = self.writer.delete_at(&self.root, key);
+        self.root = self.abort_on_error(res)?;
+        Ok(())
+    }
+
+    /// search searches for a specific key, observing the writes of the transaction.
+    pub fn search(&self, key: String) -> Result<KeyValuePair, Error> {
+        self.check_aborted()?;
+        self.writer.search_at(&self.root, &key)
+    }
+
+    /// commit publishes the writes of the transaction by setting its root as the root of the tree.
+    pub fn commit(mut self) -> Result<(), Error> {
+        self.check_aborted()?;
+        self.writer.set_root(self.root.clone())?;
+        self.committed = true;
+        Ok(())
+    }
+
+    /// rollback discards the writes of the transaction.
+    pub fn rollback(self) {
+        // Dropping an uncommitted transaction rolls it back.
+    }
+
+    /// check_aborted fails with TransactionAborted if a write of the transaction failed partway.
+    fn check_aborted(&self) -> Result<(), Error> {
+        if self.aborted {
+            return Err(Error::TransactionAborted);
+        }
+        Ok(())
+    }
+
+    /// abort_on_error aborts the transaction if a write which passed its checks failed nonetheless,
+    /// by then it might have written some of its pages.
+    fn abort_on_error<T>(&mut self, res: Result<T, Error>) -> Result<T, Error> {
+        if res.is_err() {
+            self.aborted = true;
+        }
+        res
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.writer.rollback();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn commit_publishes_all_writes() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_commit")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_commit/db"))
+            .b_parameter(2)
+            .build()?;
+        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+
+        let mut transaction = btree.begin()?;
+        for i in 0..50 {
+            transaction.insert(KeyValuePair::new(format!("{:02}", i), i.to_string()))?;
+        }
+        transaction.delete(Key("a".to_string()))?;
+        transaction.upsert(KeyValuePair::new("07".to_string(), "seven".to_string()))?;
+        // A write refused up front leaves the transaction usable.
+        let res = transaction.insert(KeyValuePair::new("08".to_string(), "eight".to_string()));
+        assert!(matches!(res, Err(Error::KeyAlreadyExists)));
+        assert_eq!(transaction.search("07".to_string())?.value, "seven");
+        transaction.commit()?;
+
+        assert!(matches!(
+            btree.search("a".to_string()),
+            Err(Error::KeyNotFound)
+        ));
+        assert_eq!(btree.search("07".to_string())?.value, "seven");
+        assert_eq!(btree.iter()?.count(), 50);
+
+        // The committed transaction is recovered as a whole.
+        drop(btree);
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_commit/db"))
+            .b_parameter(2)
+            .open()?;
+        assert_eq!(btree.search("49".to_string())?.value, "49");
+        assert_eq!(btree.iter()?.count(), 50);
+        Ok(())
+    }
+
+    #[test]
+    fn rollback_discards_all_writes() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::fs;
+        use std::path::Path;
+
+        fs::create_dir_all("/tmp/btree_rollback")?;
+        let btree = BTreeBuilder::new()
+            .path(Path::new("/tmp/btree_rollback/db"))
+            .b_parameter(2)
+            .build()?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("{:02}", i), i.to_string()))?;
+        }
+
+        let mut transaction = btree.begin()?;
+        for i in 20..40 {
+            transaction.insert(KeyValuePair::new(format!("{:02}", i), i.to_string()))?;
+        }
+        for i in 0..10 {
+            transaction.delete(Key(format!("{:02}", i)))?;
+        }
+        transaction.rollback();
+
+        {
+            // Dropping a transaction rolls it back as well.
+            let mut transaction = btree.begin()?;
+            transaction.delete(Key("10".to_string()))?;
+        }
+
+        let keys = btree
+            .iter()?
+            .map(|pair| pair.map(|pair| pair.key))
+            .collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(
+            keys,
+            (0..20).map(|i| format!("{:02}", i)).collect::<Vec<_>>()
+        );
+
+        // Pages written by the rolled back transactions are reused.
+        let file_len = fs::metadata("/tmp/btree_rollback/db")?.len();
+        let mut transaction = btree.begin()?;
+        for i in 20..40 {
+            transaction.insert(KeyValuePair::new(format!("{:02}", i), i.to_string()))?;
+        }
+        transaction.commit()?;
+        assert_eq!(fs::metadata("/tmp/btree_rollback/db")?.len(), file_len);
+        assert_eq!(btree.iter()?.count(), 40);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_write_aborts_transaction() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{KeyValuePair, Offset};
+        use crate::page::Page;
+        use crate::page_layout::PAGE_SIZE;
+        use crate::storage::{Access, Backend, MemoryBackend, PageStore, Storage};
+        use std::path::Path;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        /// FailingBackend fails every page write once the number of writes left runs out.
+        struct FailingBackend {
+            inner: MemoryBackend,
+            writes_left: Arc<AtomicUsize>,
+        }
+        struct FailingPages {
+            inner: Box<dyn PageStore>,
+            writes_left: Arc<AtomicUsize>,
+        }
+        impl Backend for FailingBackend {
+            fn open_pages(&self, path: &Path, access: Access) -> Result<Box<dyn PageStore>, Error> {
+                Ok(Box::new(FailingPages {
+                    inner: self.inner.open_pages(path, access)?,
+                    writes_left: Arc::clone(&self.writes_left),
+                }))
+            }
+            fn open_log(&self, path: &Path, access: Access) -> Result<Box<dyn Storage>, Error> {
+                self.inner.open_log(path, access)
+            }
+            fn exists(&self, path: &Path) -> bool {
+                self.inner.exists(path)
+            }
+            fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+                self.inner.rename(from, to)
+            }
+        }
+        impl PageStore for FailingPages {
+            fn read_page(&self, offset: &Offset) -> Result<Page, Error> {
+                self.inner.read_page(offset)
+            }
+            fn write_page(&mut self, offset: &Offset, page: &Page) -> Result<(), Error> {
+                self.writes_left
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                        left.checked_sub(1)
+                    })
+                    .map_err(|_| Error::UnexpectedError)?;
+                self.inner.write_page(offset, page)
+            }
+            fn allocate_page(&mut self) -> Result<Offset, Error> {
+                self.inner.allocate_page()
+            }
+            fn num_pages(&self) -> usize {
+                self.inner.num_pages()
+            }
+            fn truncate(&mut self, num_pages: usize) -> Result<(), Error> {
+                self.inner.truncate(num_pages)
+            }
+            fn sync(&self) -> Result<(), Error> {
+                self.inner.sync()
+            }
+            fn try_lock(&self, shared: bool) -> Result<bool, Error> {
+                self.inner.try_lock(shared)
+            }
+        }
+
+        let writes_left = Arc::new(AtomicUsize::new(usize::MAX));
+        let btree = BTreeBuilder::new()
+            .path("db")
+            .b_parameter(2)
+            .backend(FailingBackend {
+                inner: MemoryBackend::new(),
+                writes_left: Arc::clone(&writes_left),
+            })
+            .build()?;
+        btree.insert(KeyValuePair::new("a".to_string(), "shalom".to_string()))?;
+
+        let mut transaction = btree.begin()?;
+        for i in 0..20 {
+            transaction.insert(KeyValuePair::new(format!("{:02}", i), i.to_string()))?;
+        }
+        // The large value is written to several overflow pages, the write fails partway.
+        writes_left.store(1, Ordering::SeqCst);
+        let res = transaction.insert(KeyValuePair::new(
+            "v".to_string(),
+            "v".repeat(PAGE_SIZE * 4),
+        ));
+        assert!(matches!(res, Err(Error::UnexpectedError)));
+        writes_left.store(usize::MAX, Ordering::SeqCst);
+        let res = transaction.insert(KeyValuePair::new("b".to_string(), "hello".to_string()));
+        assert!(matches!(res, Err(Error::TransactionAborted)));
+        assert!(matches!(
+            transaction.search("00".to_string()),
+            Err(Error::TransactionAborted)
+        ));
+        assert!(matches!(
+            transaction.commit(),
+            Err(Error::TransactionAborted)
+        ));
+
+        // The aborted transaction was rolled back, the tree is still writable.
+        assert_eq!(btree.iter()?.count(), 1);
+        btree.insert(KeyValuePair::new("b".to_string(), "hello".to_string()))?;
+        assert_eq!(btree.iter()?.count(), 2);
+        Ok(())
+    }
+}
@@ -1,42 +1,388 @@
+use crate::checksum::crc32c;
 use crate::error::Error;
 use crate::node_type::Offset;
+use crate::page::Value;
 use crate::page_layout::PTR_SIZE;
+use crate::storage::{Access, Backend, Storage};
 use std::convert::TryFrom;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::path::Path;
+use uuid::Uuid;
+
+/// Wal header layout (Twenty-four bytes in total)
+///
+/// | MAGIC "BTREEWAL" - 8 bytes | Tree file id - 16 bytes |
+/// The id ties the wal to the tree file whose superblock carries the same id.
+const MAGIC: [u8; MAGIC_SIZE] = *b"BTREEWAL";
+const MAGIC_OFFSET: usize = 0;
+const MAGIC_SIZE: usize = 8;
+const ID_OFFSET: usize = MAGIC_OFFSET + MAGIC_SIZE;
+const ID_SIZE: usize = 16;
+const HEADER_SIZE: usize = ID_OFFSET + ID_SIZE;
+
+/// Wal record layout (Forty-eight bytes in total)
+///
+/// Records follow the header.
+/// | Payload length - 4 bytes | Sequence number - 8 bytes | Checksum - 4 bytes |
+/// | Root offset - 8 bytes | First freelist trunk page offset - 8 bytes | Number of pages - 8 bytes |
+/// | Oldest version - 8 bytes |
+/// The sequence number is the version committed by the record, records following the first one
+/// are numbered consecutively. The checksum is the CRC32C of the rest of the record.
+const PAYLOAD_LEN_OFFSET: usize = 0;
+const PAYLOAD_LEN_SIZE: usize = 4;
+const SEQUENCE_OFFSET: usize = PAYLOAD_LEN_OFFSET + PAYLOAD_LEN_SIZE;
+const CHECKSUM_OFFSET: usize = SEQUENCE_OFFSET + PTR_SIZE;
+const CHECKSUM_SIZE: usize = 4;
+const PAYLOAD_OFFSET: usize = CHECKSUM_OFFSET + CHECKSUM_SIZE;
+const PAYLOAD_SIZE: usize = PTR_SIZE * 4;
+const RECORD_SIZE: usize = PAYLOAD_OFFSET + PAYLOAD_SIZE;
+
+/// Record is a commit logged to the wal, the root committed along with the free pages
+/// of the tree file at that point so that both are recovered together.
+/// The superblock is only rewritten by checkpoints, until then the tree is recovered from the last record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// The version committed, versions are numbered from one.
+    pub version: usize,
+    pub root: Offset,
+    /// The first freelist trunk page, Offset(0) if there are no free pages.
+    pub freelist: Offset,
+    /// The number of pages in use by the tree file, pages past it were written by uncommitted writes.
+    pub num_pages: usize,
+    /// The oldest version whose pages were not reused as of the commit.
+    pub oldest_version: usize,
+}
+
+impl Record {
+    /// encode frames the record as an entry of the log.
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut data = [0x00; RECORD_SIZE];
+        data[PAYLOAD_LEN_OFFSET..SEQUENCE_OFFSET]
+            .clone_from_slice(&(PAYLOAD_SIZE as u32).to_be_bytes());
+        data[SEQUENCE_OFFSET..CHECKSUM_OFFSET].clone_from_slice(&self.version.to_be_bytes());
+        let fields = [
+            self.root.0,
+            self.freelist.0,
+            self.num_pages,
+            self.oldest_version,
+        ];
+        for (i, field) in fields.iter().enumerate() {
+            let offset = PAYLOAD_OFFSET + i * PTR_SIZE;
+            data[offset..offset + PTR_SIZE].clone_from_slice(&field.to_be_bytes());
+        }
+        let checksum = record_checksum(&data);
+        data[CHECKSUM_OFFSET..PAYLOAD_OFFSET].clone_from_slice(&checksum.to_be_bytes());
+        data
+    }
+
+    /// decode returns the record framed as an entry of the log, or None if it was not fully written
+    /// or does not carry the expected version.
+    fn decode(data: &[u8; RECORD_SIZE], version: Option<usize>) -> Result<Option<Record>, Error> {
+        let payload_len = read_u32(&data[PAYLOAD_LEN_OFFSET..SEQUENCE_OFFSET])?;
+        let Value(sequence) = Value::try_from(&data[SEQUENCE_OFFSET..CHECKSUM_OFFSET])?;
+        let checksum = read_u32(&data[CHECKSUM_OFFSET..PAYLOAD_OFFSET])?;
+        // A stale record left over from a previous log does not carry the expected sequence number.
+        if payload_len as usize != PAYLOAD_SIZE
+            || version.is_some_and(|version| version != sequence)
+            || checksum != record_checksum(data)
+        {
+            return Ok(None);
+        }
+        let field = |i: usize| {
+            let offset = PAYLOAD_OFFSET + i * PTR_SIZE;
+            Value::try_from(&data[offset..offset + PTR_SIZE])
+        };
+        Ok(Some(Record {
+            version: sequence,
+            root: Offset(field(0)?.0),
+            freelist: Offset(field(1)?.0),
+            num_pages: field(2)?.0,
+            oldest_version: field(3)?.0,
+        }))
+    }
+}
+
+/// read_u32 reads a four byte field of a framed record.
+fn read_u32(data: &[u8]) -> Result<u32, Error> {
+    let bytes = <[u8; 4]>::try_from(data)
+        .map_err(|_| Error::TryFromSliceError("Unexpected Error: Expected a four byte field."))?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+/// read_header returns the tree file id found in the header of a wal, None if the header was never fully written.
+fn read_header(storage: &dyn Storage) -> Result<Option<Uuid>, Error> {
+    if storage.size()? < HEADER_SIZE {
+        return Ok(None);
+    }
+    let mut header = [0x00; HEADER_SIZE];
+    storage.read_at(&mut header, 0)?;
+    if header[MAGIC_OFFSET..ID_OFFSET] != MAGIC {
+        return Err(Error::InvalidHeader("not a wal file"));
+    }
+    Uuid::from_slice(&header[ID_OFFSET..HEADER_SIZE])
+        .map(Some)
+        .map_err(|_| Error::InvalidHeader("invalid tree file id"))
+}
+
+/// record_checksum returns the checksum of a framed record, not including the checksum itself.
+fn record_checksum(data: &[u8; RECORD_SIZE]) -> u32 {
+    let mut covered = data.to_vec();
+    covered.drain(CHECKSUM_OFFSET..PAYLOAD_OFFSET);
+    crc32c(&covered)
+}
 
 pub struct Wal {
-    file: File,
+    storage: Box<dyn Storage>,
+    /// The version committed by the first record in the log.
+    first_version: usize,
+    /// The number of records in the log.
+    len: usize,
 }
 
 impl Wal {
-    pub fn new(parent_directoy: PathBuf) -> Result<Self, Error> {
-        let fd = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .truncate(true)
-            .open(parent_directoy.join("wal"))?;
+    /// new creates an empty wal for the tree file with the given id, truncating any existing wal at path.
+    pub fn new(backend: &dyn Backend, path: &Path, id: Uuid) -> Result<Self, Error> {
+        let mut wal = Self {
+            storage: backend.open_log(path, Access::ReadWrite)?,
+            first_version: 0,
+            len: 0,
+        };
+        wal.write_header(id)?;
+        Ok(wal)
+    }
+
+    /// open opens the wal at path (creating it if missing) keeping any previously logged records,
+    /// fails with InvalidHeader if the wal belongs to a tree file other than the one with the given id.
+    pub fn open(backend: &dyn Backend, path: &Path, id: Uuid) -> Result<Self, Error> {
+        Wal::from_storage(backend.open_log(path, Access::ReadWrite)?, id, true)
+    }
 
-        Ok(Self { file: fd })
+    /// open_read_only opens an existing wal for reading only, a torn tail is skipped but left in place.
+    pub fn open_read_only(backend: &dyn Backend, path: &Path, id: Uuid) -> Result<Self, Error> {
+        Wal::from_storage(backend.open_log(path, Access::ReadOnly)?, id, false)
+    }
+
+    /// read_id returns the id of the tree file the existing wal at path belongs to,
+    /// None if its header was never fully written. Fails with InvalidHeader if it is not a wal.
+    pub fn read_id(backend: &dyn Backend, path: &Path) -> Result<Option<Uuid>, Error> {
+        read_header(backend.open_log(path, Access::ReadOnly)?.as_ref())
+    }
+
+    fn from_storage(storage: Box<dyn Storage>, id: Uuid, writable: bool) -> Result<Self, Error> {
+        let mut wal = Self {
+            storage,
+            first_version: 0,
+            len: 0,
+        };
+        // A missing header was never fully written, nothing was logged after it.
+        let header_id = match read_header(wal.storage.as_ref())? {
+            Some(header_id) => header_id,
+            None => {
+                if writable {
+                    wal.write_header(id)?;
+                }
+                return Ok(wal);
+            }
+        };
+        if header_id != id {
+            return Err(Error::InvalidHeader("wal belongs to a different tree file"));
+        }
+        wal.recover(writable)?;
+        Ok(wal)
+    }
+
+    fn write_header(&mut self, id: Uuid) -> Result<(), Error> {
+        let mut header = [0x00; HEADER_SIZE];
+        header[MAGIC_OFFSET..ID_OFFSET].clone_from_slice(&MAGIC);
+        header[ID_OFFSET..HEADER_SIZE].clone_from_slice(id.as_bytes());
+        self.storage.set_size(0)?;
+        self.storage.write_at(&header, 0)?;
+        Ok(())
+    }
+
+    /// recover scans the log forward up to the first record which was not fully written, e.g. due to a crash
+    /// in the middle of a commit. Unless read only, the log is truncated right after the last complete record.
+    fn recover(&mut self, writable: bool) -> Result<(), Error> {
+        let storage_size = self.storage.size()?;
+        let mut data = [0x00; RECORD_SIZE];
+        let mut len = 0;
+        while HEADER_SIZE + (len + 1) * RECORD_SIZE <= storage_size {
+            self.storage
+                .read_at(&mut data, HEADER_SIZE + len * RECORD_SIZE)?;
+            let version = match len {
+                0 => None,
+                len => Some(self.first_version + len),
+            };
+            match Record::decode(&data, version)? {
+                Some(record) if len == 0 => self.first_version = record.version,
+                Some(_) => {}
+                None => break,
+            }
+            len += 1;
+        }
+        self.len = len;
+        let end = self.size();
+        if writable && storage_size != end {
+            self.storage.set_size(end)?;
+            self.storage.sync()?;
+        }
+        Ok(())
+    }
+
+    /// is_empty returns true if no record was ever logged.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
 
     pub fn get_root(&mut self) -> Result<Offset, Error> {
-        let mut buff: [u8; PTR_SIZE] = [0x00; PTR_SIZE];
-        let file_len = self.file.seek(SeekFrom::End(0))? as usize;
-        let mut root_offset: usize = 0;
-        if file_len > 0 {
-            root_offset = (file_len / PTR_SIZE - 1) * PTR_SIZE;
+        Ok(self.get_record()?.root)
+    }
+
+    /// get_record returns the last logged record.
+    pub fn get_record(&mut self) -> Result<Record, Error> {
+        self.get_record_at(self.first_version + self.len.saturating_sub(1))
+    }
+
+    /// size returns the size of the log in bytes.
+    pub fn size(&self) -> usize {
+        HEADER_SIZE + self.len * RECORD_SIZE
+    }
+
+    /// first_version returns the version committed by the first record in the log,
+    /// earlier versions were checkpointed.
+    pub fn first_version(&self) -> usize {
+        self.first_version
+    }
+
+    /// get_root_at returns the root logged by the given version.
+    pub fn get_root_at(&mut self, version: usize) -> Result<Offset, Error> {
+        Ok(self.get_record_at(version)?.root)
+    }
+
+    fn get_record_at(&mut self, version: usize) -> Result<Record, Error> {
+        if self.is_empty()
+            || version < self.first_version
+            || version >= self.first_version + self.len
+        {
+            return Err(Error::UnexpectedError);
         }
-        self.file.seek(SeekFrom::Start(root_offset as u64))?;
-        self.file.read_exact(&mut buff)?;
-        Offset::try_from(buff)
+        let mut data = [0x00; RECORD_SIZE];
+        let index = version - self.first_version;
+        self.storage
+            .read_at(&mut data, HEADER_SIZE + index * RECORD_SIZE)?;
+        Record::decode(&data, Some(version))?.ok_or(Error::UnexpectedError)
+    }
+
+    /// append logs a commit, the record is durable once the wal is synced.
+    /// The first record may commit any version, the following ones commit consecutive versions.
+    pub fn append(&mut self, record: &Record) -> Result<(), Error> {
+        if self.is_empty() {
+            self.first_version = record.version;
+        } else if record.version != self.first_version + self.len {
+            return Err(Error::UnexpectedError);
+        }
+        let data = record.encode();
+        self.storage.write_at(&data, self.size())?;
+        self.len += 1;
+        Ok(())
     }
 
-    pub fn set_root(&mut self, offset: Offset) -> Result<(), Error> {
-        self.file.seek(SeekFrom::End(0))?;
-        self.file.write_all(&offset.0.to_be_bytes())?;
+    /// sync flushes the records appended so far to disk.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.storage.sync()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn open_stops_at_torn_record() -> Result<(), Error> {
+        use crate::node_type::Offset;
+        use crate::storage::FileBackend;
+        use crate::wal::{Record, Wal, HEADER_SIZE, RECORD_SIZE};
+        use std::fs::{self, OpenOptions};
+        use std::io::{Seek, SeekFrom, Write};
+        use std::path::Path;
+        use uuid::Uuid;
+
+        fs::create_dir_all("/tmp/btree_wal")?;
+        let path = Path::new("/tmp/btree_wal/db-wal");
+        let id = Uuid::new_v4();
+        let record = |i: usize| Record {
+            version: i,
+            root: Offset(i * 4096),
+            freelist: Offset(0),
+            num_pages: i + 1,
+            oldest_version: 1,
+        };
+        let mut wal = Wal::new(&FileBackend, path, id)?;
+        for i in 1..=3 {
+            wal.append(&record(i))?;
+        }
+        drop(wal);
+
+        // A partially written record is dropped.
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(&record(4).encode()[..RECORD_SIZE / 2])?;
+        let mut wal = Wal::open(&FileBackend, path, id)?;
+        assert_eq!(wal.size(), HEADER_SIZE + 3 * RECORD_SIZE);
+        assert_eq!(wal.get_record()?, record(3));
+
+        // So is a damaged record along with any record following it.
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.seek(SeekFrom::Start((HEADER_SIZE + RECORD_SIZE + 20) as u64))?;
+        file.write_all(&[0xFF])?;
+        let mut wal = Wal::open(&FileBackend, path, id)?;
+        assert_eq!(wal.size(), HEADER_SIZE + RECORD_SIZE);
+        assert_eq!(wal.get_record()?, record(1));
+        assert_eq!(fs::metadata(path)?.len(), wal.size() as u64);
+
+        // Records are appended right after the last complete one.
+        assert!(wal.append(&record(3)).is_err());
+        wal.append(&record(2))?;
+        let mut wal = Wal::open(&FileBackend, path, id)?;
+        assert_eq!(wal.size(), HEADER_SIZE + 2 * RECORD_SIZE);
+        assert_eq!(wal.get_record()?, record(2));
+
+        // A log may start from any version.
+        let mut wal = Wal::new(&FileBackend, path, id)?;
+        wal.append(&record(7))?;
+        wal.append(&record(8))?;
+        let mut wal = Wal::open(&FileBackend, path, id)?;
+        assert_eq!(wal.first_version(), 7);
+        assert_eq!(wal.get_root_at(8)?, Offset(8 * 4096));
+        assert!(wal.get_root_at(6).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn open_checks_tree_file_id() -> Result<(), Error> {
+        use crate::error::Error;
+        use crate::storage::FileBackend;
+        use crate::wal::Wal;
+        use std::fs;
+        use std::path::Path;
+        use uuid::Uuid;
+
+        fs::create_dir_all("/tmp/btree_wal_id")?;
+        let path = Path::new("/tmp/btree_wal_id/db-wal");
+        let id = Uuid::new_v4();
+        Wal::new(&FileBackend, path, id)?;
+        assert!(Wal::open(&FileBackend, path, id).is_ok());
+        assert_eq!(Wal::read_id(&FileBackend, path)?, Some(id));
+        assert!(matches!(
+            Wal::open(&FileBackend, path, Uuid::new_v4()),
+            Err(Error::InvalidHeader(_))
+        ));
+
+        fs::write(path, [0xAB; 64])?;
+        assert!(matches!(
+            Wal::open(&FileBackend, path, id),
+            Err(Error::InvalidHeader(_))
+        ));
         Ok(())
     }
 }